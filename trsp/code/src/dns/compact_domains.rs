@@ -0,0 +1,184 @@
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::BufWriter,
+    path::PathBuf,
+};
+use fst::{Set, SetBuilder, raw::{Fst, Node}};
+use memmap2::Mmap;
+
+use super::domains::Domains;
+
+
+const TMP_FILENAME_SUFFIX: &str = ".tmp";
+
+
+// Bytes of the FST, in memory or mapped from the file
+enum FstData {
+    Memory(Vec<u8>),
+    Mmap(Mmap),
+}
+
+impl AsRef<[u8]> for FstData {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            FstData::Memory(data) => data,
+            FstData::Mmap(mmap) => mmap,
+        }
+    }
+}
+
+
+// Immutable set of imported names. Names are stored with reversed labels
+// ("*.example.com" -> "com.example.*") in an FST, so the name and all its
+// wildcards are checked in one pass. "+.example.com" (RPZ wildcard) matches
+// only subdomains. Keyword and regex rules are in `patterns`
+pub struct CompactDomains {
+    set: Set<FstData>,
+    // "@@" names of sources, they cancel matches of the set and patterns
+    exceptions: Set<FstData>,
+    patterns: Domains,
+}
+
+impl CompactDomains {
+    pub fn empty() -> Self {
+        Self {
+            set: memory_set(vec![]).unwrap(),
+            exceptions: memory_set(vec![]).unwrap(),
+            patterns: Domains::new(Some(0)),
+        }
+    }
+
+    // Keys and exception keys are reversed names (see reverse_name), patterns
+    // are keyword and regex rules without names. If `mmap_filepath` is set,
+    // the FST of keys is written to the file and mapped from it
+    pub fn build(
+        mut keys: Vec<String>,
+        exception_keys: Vec<String>,
+        patterns: Domains,
+        mmap_filepath: Option<&PathBuf>,
+    ) -> Result<Self, Box<dyn Error>>
+    {
+        let exceptions = memory_set(exception_keys)?;
+        let data = match mmap_filepath {
+            None => return Ok(Self { set: memory_set(keys)?, exceptions, patterns }),
+            Some(filepath) => {
+                keys.sort_unstable();
+                keys.dedup();
+                // The file of the previous set can be still mapped,
+                // so the new one replaces it with rename
+                let mut tmp_filepath = filepath.clone().into_os_string();
+                tmp_filepath.push(TMP_FILENAME_SUFFIX);
+                let mut builder = SetBuilder::new(BufWriter::new(File::create(&tmp_filepath)?))?;
+                builder.extend_iter(keys.iter())?;
+                builder.finish()?;
+                fs::rename(&tmp_filepath, filepath)?;
+                let file = File::open(filepath)?;
+                // The file is only replaced by rename, never modified in place
+                FstData::Mmap(unsafe { Mmap::map(&file)? })
+            },
+        };
+        Ok(Self {
+            set: Set::new(data)?,
+            exceptions,
+            patterns,
+        })
+    }
+
+    pub fn reverse_name(name: &str) -> String {
+        name.rsplit('.').collect::<Vec<&str>>().join(".")
+    }
+
+    pub fn count(&self) -> usize {
+        self.set.len()
+    }
+
+    pub fn patterns_count(&self) -> usize {
+        self.patterns.patterns_count()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        if contains_name(&self.exceptions, name) {
+            return false
+        }
+        contains_name(&self.set, name) || self.patterns.is_match_patterns(name)
+    }
+}
+
+fn memory_set(mut keys: Vec<String>) -> Result<Set<FstData>, fst::Error> {
+    keys.sort_unstable();
+    keys.dedup();
+    Set::new(FstData::Memory(Set::from_iter(keys.iter())?.into_fst().into_inner()))
+}
+
+// Walk the FST by labels from the TLD, "*.<suffix>" and "+.<suffix>"
+// are checked at every label boundary
+fn contains_name(set: &Set<FstData>, name: &str) -> bool {
+    let fst = set.as_fst();
+    let mut node = fst.root();
+    for (i, label) in name.rsplit('.').enumerate() {
+        if i > 0 {
+            node = match step(fst, node, b'.') {
+                Some(n) => n,
+                None => return false,
+            };
+            let is_wildcard = |byte| step(fst, node, byte).map_or(false, |n| n.is_final());
+            if is_wildcard(b'*') || is_wildcard(b'+') {
+                return true
+            }
+        }
+        for byte in label.bytes() {
+            node = match step(fst, node, byte) {
+                Some(n) => n,
+                None => return false,
+            };
+        }
+    }
+    if node.is_final() {
+        return true
+    }
+    // "*.name" matches the name itself
+    step(fst, node, b'.')
+        .and_then(|n| step(fst, n, b'*'))
+        .map_or(false, |n| n.is_final())
+}
+
+fn step<'f>(fst: &'f Fst<FstData>, node: Node<'f>, byte: u8) -> Option<Node<'f>> {
+    node.find_input(byte).map(|i| fst.node(node.transition_addr(i)))
+}
+
+
+#[test]
+fn test_compact_domains_contains() {
+    let keys: Vec<String> = ["blocked.ru", "*.wildcard.ru", "*.com.ua", "exact.org", "+.subdomains.ru"]
+        .iter()
+        .map(|n| CompactDomains::reverse_name(n))
+        .collect();
+    let mut patterns = Domains::new(Some(0));
+    patterns.add_keyword("casino");
+
+    let workdir = tempfile::tempdir().unwrap();
+    let mmap_filepath = workdir.path().join("imported.fst");
+    for mmap in [None, Some(&mmap_filepath)] {
+        let domains = CompactDomains::build(keys.clone(), vec![], Domains::new(Some(0)), mmap).unwrap();
+        assert_eq!(domains.count(), 5);
+        assert!(domains.contains("blocked.ru"));
+        assert!(!domains.contains("sub.blocked.ru"));
+        assert!(!domains.contains("notblocked.ru"));
+        assert!(!domains.contains("ru"));
+        assert!(domains.contains("wildcard.ru"));
+        assert!(domains.contains("a.b.wildcard.ru"));
+        assert!(!domains.contains("notwildcard.ru"));
+        assert!(domains.contains("shop.com.ua"));
+        assert!(!domains.contains("exact.org.ua"));
+        assert!(!domains.contains(""));
+        assert!(domains.contains("a.subdomains.ru"));
+        assert!(!domains.contains("subdomains.ru"));
+    }
+    let exception_keys = vec![CompactDomains::reverse_name("ok.wildcard.ru")];
+    let domains = CompactDomains::build(keys, exception_keys, patterns, None).unwrap();
+    assert!(domains.contains("best-casino.net"));
+    assert!(domains.contains("a.wildcard.ru"));
+    assert!(!domains.contains("ok.wildcard.ru"));
+    assert!(!CompactDomains::empty().contains("blocked.ru"));
+}
@@ -0,0 +1,61 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
+use serde::Deserialize;
+
+use super::domain_source::DomainSourceConfig;
+use super::geoip::GeoIpConfig;
+use super::upstreams::{UpstreamSpec, UpstreamsConfig};
+
+
+// [dns] section of the config file (--config), for settings that
+// don't fit to command line options
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct DnsConfig {
+    #[serde(default)]
+    pub sources: Vec<DomainSourceConfig>,
+    #[serde(default)]
+    pub geoip: GeoIpConfig,
+    // File of local records, see LocalRecords
+    pub local_records: Option<PathBuf>,
+    #[serde(default)]
+    pub zones: Vec<ZoneConfig>,
+    #[serde(default)]
+    pub upstreams: UpstreamsConfig,
+}
+
+// Names of the zone are forwarded to its upstreams only
+#[derive(Debug, Deserialize, Clone)]
+pub struct ZoneConfig {
+    pub zone: String,
+    // URLs of UpstreamSpec
+    pub upstreams: Vec<UpstreamSpec>,
+}
+
+impl DnsConfig {
+    // Empty path means "no config file", defaults are used
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        if path.is_empty() {
+            return Ok(Self::default())
+        }
+        let config = ::config::Config::builder()
+            .add_source(::config::File::from(Path::new(path)))
+            .build()?;
+        match config.get::<DnsConfig>("dns") {
+            Ok(c) => Ok(c),
+            Err(::config::ConfigError::NotFound(_)) => Ok(Self::default()),
+            Err(e) => Err(format!("Config file '{}': {}", path, e).into()),
+        }
+    }
+}
+
+
+#[test]
+fn test_dns_config_load_example() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/config.example.toml");
+    let config = DnsConfig::load(path).unwrap();
+    assert_eq!(config.sources.len(), 3);
+    assert_eq!(config.sources[1].name, "zapret_nxdomains");
+    assert!(DnsConfig::load("").unwrap().sources.is_empty());
+}
@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use serde::Serialize;
+
+use super::domain_source::{normalize_domain_name, SourceRole};
+use super::explain::RuleMatch;
+use super::patterns::{PatternMatch, Patterns, EXCEPTION_PREFIX, KEYWORD_PREFIX, REGEX_PREFIX};
+
+
+const FULL_PREFIX: &str = "full:";
+const DOMAIN_PREFIX: &str = "domain:";
+const WILDCARD_PREFIX: &str = "*.";
+const ADBLOCK_PREFIX: &str = "||";
+const ADBLOCK_SUFFIX: &str = "^";
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleKind {
+    // "example.com", "full:example.com"
+    Exact,
+    // The domain and all subdomains: "*.example.com", "domain:example.com", "||example.com^"
+    Suffix,
+    // Substring of the name: "keyword:casino"
+    Keyword,
+    // "regexp:^rr[0-9]+---sn-.*\.googlevideo\.com$"
+    Regex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub kind: RuleKind,
+    pub value: String,
+    // "@@" rule, cancels matches of other rules of the list
+    pub is_exception: bool,
+}
+
+impl Rule {
+    // None for empty lines and comments
+    pub fn parse(line: &str) -> Result<Option<Self>, String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None)
+        }
+        let (is_exception, line) = match line.strip_prefix(EXCEPTION_PREFIX) {
+            Some(l) => (true, l),
+            None => (false, line),
+        };
+        let (kind, value) = if let Some(v) = line.strip_prefix(REGEX_PREFIX) {
+            regex::Regex::new(v).map_err(|e| e.to_string())?;
+            (RuleKind::Regex, String::from(v))
+        } else if let Some(v) = line.strip_prefix(KEYWORD_PREFIX) {
+            if v.is_empty() {
+                return Err(String::from("empty keyword"))
+            }
+            (RuleKind::Keyword, v.to_lowercase())
+        } else if let Some(v) = line.strip_prefix(DOMAIN_PREFIX).or_else(|| line.strip_prefix(WILDCARD_PREFIX)) {
+            (RuleKind::Suffix, Rule::domain(v)?)
+        } else if let Some(v) = line.strip_prefix(ADBLOCK_PREFIX) {
+            (RuleKind::Suffix, Rule::domain(v.strip_suffix(ADBLOCK_SUFFIX).unwrap_or(v))?)
+        } else {
+            (RuleKind::Exact, Rule::domain(line.strip_prefix(FULL_PREFIX).unwrap_or(line))?)
+        };
+        Ok(Some(Self { kind, value, is_exception }))
+    }
+
+    fn domain(value: &str) -> Result<String, String> {
+        let domain = normalize_domain_name(value)?;
+        if domain.contains('*') {
+            return Err(format!("invalid domain '{}'", value))
+        }
+        Ok(domain)
+    }
+}
+
+
+// Rules are stored with the index of their line in DomainRules
+#[derive(Debug, Default)]
+struct RuleSet {
+    exact: HashMap<String, usize>,
+    // Suffixes without "*."
+    suffixes: HashMap<String, usize>,
+    patterns: Patterns,
+    // Line indexes of patterns.keywords() and patterns.regexes()
+    keyword_indexes: Vec<usize>,
+    regex_indexes: Vec<usize>,
+}
+
+impl RuleSet {
+    fn add(&mut self, rule: Rule, index: usize) {
+        match rule.kind {
+            RuleKind::Exact => { self.exact.entry(rule.value).or_insert(index); },
+            RuleKind::Suffix => { self.suffixes.entry(rule.value).or_insert(index); },
+            RuleKind::Keyword => {
+                if self.patterns.add_keyword(&rule.value) {
+                    self.keyword_indexes.push(index);
+                }
+            },
+            // Regexes are validated by Rule::parse
+            RuleKind::Regex => {
+                if let Ok(true) = self.patterns.add_regex(&rule.value) {
+                    self.regex_indexes.push(index);
+                }
+            },
+        }
+    }
+
+    fn build_regex_set(&mut self) -> Result<(), String> {
+        let result = self.patterns.build();
+        if result.is_err() {
+            self.regex_indexes.clear();
+        }
+        result
+    }
+
+    fn len(&self) -> usize {
+        self.exact.len() + self.suffixes.len() + self.patterns.len()
+    }
+
+    // Index of the matched rule and the matched part of the name (the name
+    // itself or its parent for suffix rules). Suffixes are looked up by
+    // slices of the name, without allocations
+    fn find<'n>(&self, name: &'n str) -> Option<(usize, &'n str)> {
+        if let Some(index) = self.exact.get(name) {
+            return Some((*index, name))
+        }
+        let mut suffix = name;
+        loop {
+            if let Some(index) = self.suffixes.get(suffix) {
+                return Some((*index, suffix))
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => break,
+            }
+        }
+        match self.patterns.find(name)? {
+            PatternMatch::Keyword(i) => Some((self.keyword_indexes[i], name)),
+            PatternMatch::Regex(i) => Some((self.regex_indexes[i], name)),
+        }
+    }
+}
+
+
+// Rules of the included or excluded list. The name matches the list
+// if it matches any rule and doesn't match any exception ("@@") rule.
+// Regex rules match after build()
+#[derive(Debug, Default)]
+pub struct DomainRules {
+    rules: RuleSet,
+    exceptions: RuleSet,
+    // Original lines of the rules, to write the list back
+    lines: Vec<String>,
+    // Line numbers in the file the rules are loaded from
+    line_numbers: Vec<Option<u64>>,
+}
+
+impl DomainRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, line: &str) -> Result<(), String> {
+        self.add_line(line, None)
+    }
+
+    pub fn add_line(&mut self, line: &str, line_number: Option<u64>) -> Result<(), String> {
+        let rule = match Rule::parse(line)? {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        let index = self.lines.len();
+        if rule.is_exception {
+            self.exceptions.add(rule, index);
+        } else {
+            self.rules.add(rule, index);
+        }
+        self.lines.push(String::from(line.trim()));
+        self.line_numbers.push(line_number);
+        Ok(())
+    }
+
+    // Regex set must be rebuilt with build() after adding
+    pub fn build(&mut self) -> Result<(), String> {
+        let mut err = vec![];
+        if let Err(e) = self.rules.build_regex_set() {
+            err.push(e);
+        }
+        if let Err(e) = self.exceptions.build_regex_set() {
+            err.push(format!("Exceptions: {}", e));
+        }
+        if ! err.is_empty() {
+            return Err(err.join(". "))
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len() + self.exceptions.len()
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        self.rules.find(name).is_some() && self.exceptions.find(name).is_none()
+    }
+
+    // The first matched rule and exception of the list
+    pub fn explain(&self, name: &str, list: &str, role: SourceRole) -> Vec<RuleMatch> {
+        let mut matches = vec![];
+        for rules in [&self.rules, &self.exceptions] {
+            if let Some((index, matched_name)) = rules.find(name) {
+                // Lines of added rules are parsed already
+                if let Ok(Some(rule)) = Rule::parse(&self.lines[index]) {
+                    matches.push(RuleMatch {
+                        list: String::from(list),
+                        role,
+                        rule: self.lines[index].clone(),
+                        kind: rule.kind,
+                        matched_name: String::from(matched_name),
+                        line: self.line_numbers[index],
+                        is_exception: rule.is_exception,
+                        record: None,
+                    });
+                }
+            }
+        }
+        matches
+    }
+}
+
+
+#[test]
+fn test_domain_rules_is_match() {
+    let mut rules = DomainRules::new();
+    for line in [
+        "# comment",
+        "exact.ru",
+        "full:full.ru",
+        "Сайт.РФ",
+        "*.wildcard.ru",
+        "domain:suffix.ru",
+        "keyword:casino",
+        "||adblock.ru^",
+        r"regexp:^rr[0-9]+---sn-.*\.googlevideo\.com$",
+        "@@ok.suffix.ru",
+        "@@domain:clean.wildcard.ru",
+        "",
+    ] {
+        rules.add(line).unwrap();
+    }
+    assert_eq!(rules.len(), 10);
+    assert!(rules.add("bad domain").is_err());
+    assert!(rules.add("regexp:(").is_err());
+    assert!(!rules.is_match("rr3---sn-abc.googlevideo.com"));
+    rules.build().unwrap();
+
+    assert!(rules.is_match("exact.ru"));
+    assert!(!rules.is_match("sub.exact.ru"));
+    assert!(rules.is_match("full.ru"));
+    assert!(rules.is_match("xn--80aswg.xn--p1ai"));
+    assert!(rules.is_match("wildcard.ru"));
+    assert!(rules.is_match("a.b.wildcard.ru"));
+    assert!(!rules.is_match("notwildcard.ru"));
+    assert!(rules.is_match("suffix.ru"));
+    assert!(rules.is_match("www.suffix.ru"));
+    assert!(rules.is_match("online-casino.com"));
+    assert!(rules.is_match("ads.adblock.ru"));
+    assert!(rules.is_match("rr3---sn-abc.googlevideo.com"));
+    assert!(!rules.is_match("www.googlevideo.com"));
+
+    // Exceptions
+    assert!(!rules.is_match("ok.suffix.ru"));
+    assert!(rules.is_match("www.ok.suffix.ru"));
+    assert!(!rules.is_match("clean.wildcard.ru"));
+    assert!(!rules.is_match("a.clean.wildcard.ru"));
+
+    let matches = rules.explain("a.clean.wildcard.ru", "included", SourceRole::Include);
+    assert_eq!(matches.len(), 2);
+    assert_eq!(matches[0].rule, "*.wildcard.ru");
+    assert_eq!(matches[0].matched_name, "wildcard.ru");
+    assert!(matches[1].is_exception);
+    assert_eq!(matches[1].matched_name, "clean.wildcard.ru");
+    assert!(rules.explain("other.ru", "included", SourceRole::Include).is_empty());
+}
@@ -0,0 +1,902 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display},
+    fs,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use reqwest::Url;
+use lazy_static::lazy_static;
+use regex::Regex;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+use tracing::{debug, info, warn, error};
+use super::domains::{Domains, Domain};
+use super::domain_rules::RuleKind;
+use super::explain::RuleMatch;
+use super::patterns::KEYWORD_PREFIX;
+use super::zapret::{self, RecordsIndexWriter, ZapretRecord};
+use super::geosite::parse_geosite;
+use super::rpz::{RpzState, RpzZone, TsigConfig};
+use super::list_fetcher::{self, CacheValidators, Fetched, ListFetcher, ListReader};
+
+
+const SOURCE_CACHE_FILENAME_PREFIX: &str = "_trsp_source_";
+// Side files of the cache file: "<name>\t<line>" of parsed names
+// and the registry records index (see zapret::RecordsIndexWriter)
+const ORIGINS_FILENAME_SUFFIX: &str = ".lines";
+const RECORDS_FILENAME_SUFFIX: &str = ".records";
+const SIDE_FILENAME_SUFFIXES: [&str; 2] = [ORIGINS_FILENAME_SUFFIX, RECORDS_FILENAME_SUFFIX];
+const TMP_FILENAME_SUFFIX: &str = ".tmp";
+const SOURCE_DOMAINS_HASHSET_CAP: usize = 1024;
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+// Invalid lines logged per source, the rest are only counted
+const MAX_LOGGED_ERRORS: u64 = 20;
+// Names from hosts files that are never blocked
+const HOSTS_IGNORED_NAMES: [&str; 7] = [
+    "localhost", "localhost.localdomain", "local", "broadcasthost",
+    "ip6-localhost", "ip6-loopback", "0.0.0.0",
+];
+
+
+lazy_static! {
+    static ref VALID_DOMAIN_RE: Regex = Regex::new(r"^[\p{L}\p{M}0-9\-_\.\*]*+$").unwrap();
+    static ref VALID_SOURCE_NAME_RE: Regex = Regex::new(r"^[a-zA-Z0-9\-_]+$").unwrap();
+}
+
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceFormat {
+    // zapret-info dump.csv, domain in the second column, cp1251
+    ZapretCsv,
+    // One domain per line (zapret nxdomain.txt, antifilter lists)
+    Plain,
+    // "0.0.0.0 domain [domain...]"
+    Hosts,
+    // "server=/domain/[domain/]upstream", also address=, ipset=, nftset=
+    Dnsmasq,
+    // "||domain^"
+    Adguard,
+    // v2fly/Xray geosite.dat, rules of the source "categories"
+    Geosite,
+    // Response Policy Zone, transferred with AXFR/IXFR from the source "primary"
+    Rpz,
+    // One IP or prefix per line, "1.2.3.0/24"
+    Cidr,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceRole {
+    // Domains are added to the imported (blocked) domains
+    #[default]
+    Include,
+    // Domains are removed from the imported domains of include sources
+    Exclude,
+}
+
+// [[dns.sources]] item of the config file
+#[derive(Debug, Deserialize, Clone)]
+pub struct DomainSourceConfig {
+    pub name: String,
+    pub url: Option<String>,
+    // Urls tried in order if the url fails
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    pub path: Option<PathBuf>,
+    pub format: SourceFormat,
+    #[serde(default)]
+    pub role: SourceRole,
+    // Geosite categories, e.g. "category-ru-blocked"
+    #[serde(default)]
+    pub categories: Vec<String>,
+    // RPZ primary server address, zone name and optional TSIG key
+    pub primary: Option<SocketAddr>,
+    pub zone: Option<String>,
+    pub tsig: Option<TsigConfig>,
+    // Overrides --dns-refresh-interval-secs, 0 disables the refresh
+    pub refresh_interval_secs: Option<u64>,
+    // Default depends on the role, see SourceGuards::new()
+    pub min_entries: Option<usize>,
+    pub max_shrink_percent: Option<f64>,
+    pub max_error_ratio: Option<f64>,
+    // Add addresses of zapret_csv records to the blocked prefixes
+    #[serde(default)]
+    pub ips: bool,
+}
+
+// Checks of a new source version. The version which fails them is rejected,
+// the last good cache stays in use
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceGuards {
+    // Domains, patterns and prefixes
+    pub min_entries: usize,
+    // Max shrink against the previous (cached) version, in percent
+    pub max_shrink_percent: Option<f64>,
+    // Max ratio of invalid records to all records with domains
+    pub max_error_ratio: Option<f64>,
+}
+
+impl Default for SourceGuards {
+    fn default() -> Self {
+        Self {
+            min_entries: 1,
+            max_shrink_percent: None,
+            max_error_ratio: None,
+        }
+    }
+}
+
+impl SourceGuards {
+    // Exclude list may be empty
+    pub fn new(role: SourceRole) -> Self {
+        match role {
+            SourceRole::Include => Self::default(),
+            SourceRole::Exclude => Self { min_entries: 0, ..Default::default() },
+        }
+    }
+
+    fn check(&self, entries: usize, errors_count: u64, previous_entries: Option<usize>)
+        -> Result<(), String>
+    {
+        if entries < self.min_entries {
+            return Err(format!("{} entries, min {}", entries, self.min_entries))
+        }
+        if let (Some(max_shrink_percent), Some(previous_entries)) = (self.max_shrink_percent, previous_entries) {
+            if previous_entries > entries {
+                let shrink_percent = (previous_entries - entries) as f64 * 100.0 / previous_entries as f64;
+                if shrink_percent > max_shrink_percent {
+                    return Err(format!(
+                        "{} entries, shrunk by {:.1}% from {}, max {}%",
+                        entries, shrink_percent, previous_entries, max_shrink_percent,
+                    ))
+                }
+            }
+        }
+        if let Some(max_error_ratio) = self.max_error_ratio {
+            let error_ratio = errors_count as f64 / (entries as u64 + errors_count).max(1) as f64;
+            if error_ratio > max_error_ratio {
+                return Err(format!(
+                    "{} errors of {} records, ratio {:.3}, max {}",
+                    errors_count, entries as u64 + errors_count, error_ratio, max_error_ratio,
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+// Version of the source, written next to the cache file after it
+enum SourceVersion {
+    Http(CacheValidators),
+    Rpz(RpzState),
+}
+
+impl SourceVersion {
+    async fn write(&self, cache_filepath: &Path) -> Result<(), Box<dyn Error>> {
+        match self {
+            SourceVersion::Http(validators) => validators.write(cache_filepath).await,
+            SourceVersion::Rpz(state) => state.write(cache_filepath).await,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SourceLocation {
+    Url(Url),
+    Path(PathBuf),
+    Rpz(RpzZone),
+}
+
+impl Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SourceLocation::Url(url) => write!(f, "{}", url),
+            SourceLocation::Path(path) => write!(f, "{}", path.display()),
+            SourceLocation::Rpz(rpz) => write!(f, "rpz {}@{}", rpz.zone, rpz.primary),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct DomainSource {
+    pub name: String,
+    pub location: SourceLocation,
+    pub mirrors: Vec<Url>,
+    pub format: SourceFormat,
+    pub role: SourceRole,
+    pub categories: Vec<String>,
+    pub refresh_interval_secs: Option<u64>,
+    pub guards: SourceGuards,
+    // Addresses of zapret_csv records are imported as prefixes
+    pub ips: bool,
+}
+
+impl DomainSource {
+    pub fn new(name: &str, location: SourceLocation, format: SourceFormat, role: SourceRole) -> Self {
+        Self {
+            name: String::from(name),
+            location,
+            mirrors: vec![],
+            format,
+            role,
+            categories: vec![],
+            refresh_interval_secs: None,
+            guards: SourceGuards::new(role),
+            ips: false,
+        }
+    }
+
+    pub fn from_config(config: &DomainSourceConfig) -> Result<Self, Box<dyn Error>> {
+        if ! VALID_SOURCE_NAME_RE.is_match(&config.name) {
+            return Err(format!("Invalid source name '{}'", config.name).into())
+        }
+        let location = match (&config.url, &config.path) {
+            _ if config.format == SourceFormat::Rpz => {
+                match (config.primary, &config.zone) {
+                    (Some(primary), Some(zone)) => SourceLocation::Rpz(
+                        RpzZone::new(primary, zone, config.tsig.clone())?
+                    ),
+                    _ => {
+                        return Err(format!(
+                            "RPZ source '{}' must have 'primary' and 'zone'", config.name
+                        ).into())
+                    }
+                }
+            },
+            (Some(url), None) => SourceLocation::Url(Url::parse(url)?),
+            (None, Some(path)) => SourceLocation::Path(path.clone()),
+            _ => {
+                return Err(format!(
+                    "Source '{}' must have either 'url' or 'path'", config.name
+                ).into())
+            }
+        };
+        if config.format == SourceFormat::Geosite && config.categories.is_empty() {
+            return Err(format!("Geosite source '{}' without categories", config.name).into())
+        }
+        if ! config.mirrors.is_empty() && config.url.is_none() {
+            return Err(format!("Source '{}' has mirrors without 'url'", config.name).into())
+        }
+        let mut source = Self::new(&config.name, location, config.format, config.role);
+        for mirror in &config.mirrors {
+            source.mirrors.push(Url::parse(mirror)?);
+        }
+        source.categories = config.categories.clone();
+        source.refresh_interval_secs = config.refresh_interval_secs;
+        source.ips = config.ips;
+        source.guards = SourceGuards {
+            min_entries: config.min_entries.unwrap_or(source.guards.min_entries),
+            max_shrink_percent: config.max_shrink_percent,
+            max_error_ratio: config.max_error_ratio,
+        };
+        Ok(source)
+    }
+
+    pub fn cache_filepath(&self, workdir: &Path) -> PathBuf {
+        workdir.join(format!("{}{}", SOURCE_CACHE_FILENAME_PREFIX, self.name))
+    }
+
+    // Side files are written by the parser to tmp files and
+    // replace the previous ones with the cache file
+    fn side_filepath(cache_filepath: &Path, suffix: &str, is_tmp: bool) -> PathBuf {
+        let mut path = cache_filepath.as_os_str().to_os_string();
+        path.push(suffix);
+        if is_tmp {
+            path.push(TMP_FILENAME_SUFFIX);
+        }
+        PathBuf::from(path)
+    }
+
+    // Fetch and parse source, on error domains are loaded from the cache file
+    // Domains and the refresh error if they are loaded from the cache file
+    pub async fn load(&self, workdir: &Path, fetcher: &ListFetcher)
+        -> Result<(Domains, Option<String>), Box<dyn Error>>
+    {
+        let e = match self.refresh(workdir, fetcher).await.map_err(|e| e.to_string()) {
+            Ok(domains) => return Ok((domains, None)),
+            Err(e) => e,
+        };
+        error!("Load source '{}' ({}) error: {}", self.name, self.location, e);
+        warn!(
+            "Load domains from cache file: {}",
+            &self.cache_filepath(workdir).as_path().display(),
+        );
+        match self.load_cache(workdir).await {
+            Ok(domains) => Ok((domains, Some(e))),
+            Err(cache_e) => Err(format!("{}; cache: {}", e, cache_e).into()),
+        }
+    }
+
+    pub async fn load_cache(&self, workdir: &Path) -> Result<Domains, Box<dyn Error>> {
+        let mut domains = Domains::new(Some(SOURCE_DOMAINS_HASHSET_CAP));
+        domains.read_from_file(&self.cache_filepath(workdir)).await?;
+        Ok(domains)
+    }
+
+    // Cached rules of the source matching the name or its parents. Lines are
+    // found in the origins file, sources without it are checked by the cache
+    pub async fn explain(&self, workdir: &Path, name: &str) -> Result<Vec<RuleMatch>, Box<dyn Error>> {
+        // Rule -> (kind, matched name)
+        let mut candidates: HashMap<String, (RuleKind, &str)> = HashMap::new();
+        candidates.insert(String::from(name), (RuleKind::Exact, name));
+        let mut suffix = name;
+        loop {
+            candidates.insert(format!("*.{}", suffix), (RuleKind::Suffix, suffix));
+            if suffix != name {
+                // RPZ wildcard of the parent
+                candidates.insert(format!("+.{}", suffix), (RuleKind::Suffix, suffix));
+                // Excluded parent removes its included wildcard
+                if self.role == SourceRole::Exclude {
+                    candidates.insert(String::from(suffix), (RuleKind::Suffix, suffix));
+                }
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => break,
+            }
+        }
+        let rule_match = |rule: &str, kind, matched_name: &str, line| RuleMatch {
+            list: self.name.clone(),
+            role: self.role,
+            rule: String::from(rule),
+            kind,
+            matched_name: String::from(matched_name),
+            line,
+            is_exception: false,
+            record: None,
+        };
+        let exception_match = |rule: &str, kind, matched_name: &str| RuleMatch {
+            rule: format!("@@{}", rule),
+            is_exception: true,
+            ..rule_match(rule, kind, matched_name, None)
+        };
+
+        let mut matches: Vec<RuleMatch> = vec![];
+        let cache_filepath = self.cache_filepath(workdir);
+        let origins_filepath = DomainSource::side_filepath(&cache_filepath, ORIGINS_FILENAME_SUFFIX, false);
+        if origins_filepath.exists() {
+            let file = tokio::fs::File::open(&origins_filepath).await?;
+            let mut lines = tokio::io::BufReader::new(file).lines();
+            while let Some(line) = lines.next_line().await? {
+                let (rule, line_n) = match line.split_once('\t') {
+                    Some((rule, line_n)) => (rule, line_n.parse::<u64>().ok()),
+                    None => continue,
+                };
+                if let Some((kind, matched_name)) = candidates.remove(rule) {
+                    matches.push(rule_match(rule, kind, matched_name, line_n));
+                }
+            }
+            // Decision of the registry record
+            let records_filepath = DomainSource::side_filepath(&cache_filepath, RECORDS_FILENAME_SUFFIX, false);
+            if records_filepath.exists() {
+                for m in &mut matches {
+                    if let Some(line) = m.line {
+                        m.record = zapret::find_record(&records_filepath, line).await?;
+                    }
+                }
+            }
+            return Ok(matches)
+        }
+        let domains = self.load_cache(workdir).await?;
+        for (rule, (kind, matched_name)) in &candidates {
+            if domains.get(rule).is_some() {
+                matches.push(rule_match(rule, *kind, matched_name, None));
+            }
+            if domains.get_exception(rule).is_some() {
+                matches.push(exception_match(rule, *kind, matched_name));
+            }
+        }
+        if let Some(pattern) = domains.matching_pattern(name) {
+            let kind = match pattern.starts_with(KEYWORD_PREFIX) {
+                true => RuleKind::Keyword,
+                false => RuleKind::Regex,
+            };
+            matches.push(rule_match(&pattern, kind, name, None));
+        }
+        Ok(matches)
+    }
+
+    // Fetch and parse source, the result is checked by guards and written to
+    // the cache file. If the source isn't modified, domains are loaded from the cache file
+    pub async fn refresh(&self, workdir: &Path, fetcher: &ListFetcher) -> Result<Domains, Box<dyn Error>> {
+        let cache_filepath = self.cache_filepath(workdir);
+        let start = Instant::now();
+        let validators = CacheValidators::read(&cache_filepath).await;
+        let mut res = self.fetch_and_parse(&cache_filepath, fetcher, &validators).await
+            .map_err(|e| e.to_string());
+        if let Ok(None) = res {
+            match self.load_cache(workdir).await.map_err(|e| e.to_string()) {
+                Ok(domains) => {
+                    info!(
+                        "Source '{}' ({}) isn't modified, loaded from cache in {:?}: {} domains",
+                        self.name, self.location, start.elapsed(), domains.count(),
+                    );
+                    return Ok(domains)
+                },
+                Err(e) => {
+                    warn!("Source '{}' cache error: {}, fetch it without validators", self.name, e);
+                    res = self.fetch_and_parse(&cache_filepath, fetcher, &CacheValidators::default()).await
+                        .map_err(|e| e.to_string());
+                },
+            }
+        }
+        let (domains, errors_count, version) = res?
+            .ok_or_else(|| String::from("not modified, but there is no cache"))?;
+
+        let entries = domains.entries_count();
+        let previous_entries = match self.guards.max_shrink_percent {
+            Some(_) => self.load_cache(workdir).await.ok().map(|d| d.entries_count()),
+            None => None,
+        };
+        if let Err(e) = self.guards.check(entries, errors_count, previous_entries) {
+            for suffix in SIDE_FILENAME_SUFFIXES {
+                let _ = tokio::fs::remove_file(DomainSource::side_filepath(&cache_filepath, suffix, true)).await;
+            }
+            return Err(format!("new version is rejected: {}", e).into())
+        }
+        domains.write_to_file(&cache_filepath).await?;
+        for suffix in SIDE_FILENAME_SUFFIXES {
+            let tmp_filepath = DomainSource::side_filepath(&cache_filepath, suffix, true);
+            let filepath = DomainSource::side_filepath(&cache_filepath, suffix, false);
+            if tmp_filepath.exists() {
+                tokio::fs::rename(&tmp_filepath, &filepath).await?;
+            } else if filepath.exists() {
+                // Not written for this format
+                tokio::fs::remove_file(&filepath).await?;
+            }
+        }
+        version.write(&cache_filepath).await?;
+        info!(
+            "Source '{}' ({}) loaded in {:?}: {} domains, errors: {}",
+            self.name, self.location, start.elapsed(), domains.count(), errors_count,
+        );
+        Ok(domains)
+    }
+
+    // None if the source isn't modified since the cached version
+    async fn fetch_and_parse(
+        &self,
+        cache_filepath: &Path,
+        fetcher: &ListFetcher,
+        validators: &CacheValidators,
+    ) -> Result<Option<(Domains, u64, SourceVersion)>, Box<dyn Error>>
+    {
+        let (reader, validators) = match &self.location {
+            // RPZ needs the cached version for IXFR
+            SourceLocation::Rpz(rpz) => {
+                return Ok(rpz.load(cache_filepath).await?.map(|(domains, errors_count, state)| {
+                    (domains, errors_count, SourceVersion::Rpz(state))
+                }))
+            },
+            SourceLocation::Url(url) => {
+                return self.fetch_and_parse_urls(url, cache_filepath, fetcher, validators).await
+            },
+            SourceLocation::Path(path) => {
+                (list_fetcher::open_file(path).await?, CacheValidators::default())
+            },
+        };
+        let (domains, errors_count) = self.parse(reader, cache_filepath).await?;
+        Ok(Some((domains, errors_count, SourceVersion::Http(validators))))
+    }
+
+    // The url and then mirrors in order, each with retries. The list is
+    // parsed while it's downloaded, so body errors are retried too
+    async fn fetch_and_parse_urls(
+        &self,
+        url: &Url,
+        cache_filepath: &Path,
+        fetcher: &ListFetcher,
+        validators: &CacheValidators,
+    ) -> Result<Option<(Domains, u64, SourceVersion)>, Box<dyn Error>>
+    {
+        let mut err: Vec<String> = vec![];
+        for url in std::iter::once(url).chain(self.mirrors.iter()) {
+            for attempt in 0..=fetcher.retries {
+                if attempt > 0 {
+                    tokio::time::sleep(RETRY_DELAY * attempt).await;
+                }
+                let res = match fetcher.fetch(url, validators).await.map_err(|e| e.to_string()) {
+                    Ok(Fetched::NotModified) => return Ok(None),
+                    Ok(Fetched::Modified(reader, validators)) => {
+                        self.parse(reader, cache_filepath).await
+                            .map(|r| (r, validators))
+                            .map_err(|e| e.to_string())
+                    },
+                    Err(e) => Err(e),
+                };
+                match res {
+                    Ok(((domains, errors_count), validators)) => {
+                        return Ok(Some((domains, errors_count, SourceVersion::Http(validators))))
+                    },
+                    Err(e) => {
+                        warn!("Source '{}' {} attempt {} error: {}", self.name, url, attempt + 1, e);
+                        err.push(format!("{}: {}", url, e));
+                    },
+                }
+            }
+        }
+        Err(err.join(". ").into())
+    }
+
+    // Side files are written next to the cache file as tmp ones
+    async fn parse(&self, mut reader: ListReader, cache_filepath: &Path)
+        -> Result<(Domains, u64), Box<dyn Error>>
+    {
+        // Geosite is a protobuf message, it can't be parsed line by line
+        if self.format == SourceFormat::Geosite {
+            let mut data = vec![];
+            reader.read_to_end(&mut data).await?;
+            return parse_geosite(&data, &self.categories)
+        }
+        let origins_filepath = DomainSource::side_filepath(cache_filepath, ORIGINS_FILENAME_SUFFIX, true);
+        let mut parser = SourceParser::new(&self.name, self.format);
+        parser.with_ips = self.ips;
+        parser.origins = Some(io::BufWriter::new(fs::File::create(origins_filepath)?));
+        if self.format == SourceFormat::ZapretCsv {
+            let records_filepath = DomainSource::side_filepath(cache_filepath, RECORDS_FILENAME_SUFFIX, true);
+            parser.records = Some(RecordsIndexWriter::create(&records_filepath)?);
+        }
+        let mut chunk = vec![0; READ_CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut chunk).await?;
+            if n == 0 {
+                break
+            }
+            parser.feed(&chunk[..n]);
+        }
+        parser.finish()
+    }
+}
+
+
+// Splits incoming chunks to lines and parses them according to the source format
+struct SourceParser {
+    source_name: String,
+    format: SourceFormat,
+    buf: Vec<u8>,
+    names: Vec<String>,
+    line_n: u64,
+    errors_count: u64,
+    domains: Domains,
+    // Lines of parsed names, for explain
+    origins: Option<io::BufWriter<fs::File>>,
+    // Registry records of the zapret dump
+    records: Option<RecordsIndexWriter>,
+    // Addresses of zapret records are added to prefixes
+    with_ips: bool,
+    side_error: Option<io::Error>,
+}
+
+impl SourceParser {
+    fn new(source_name: &str, format: SourceFormat) -> Self {
+        Self {
+            source_name: String::from(source_name),
+            format,
+            buf: Vec::with_capacity(100),
+            names: vec![],
+            line_n: 0,
+            errors_count: 0,
+            domains: Domains::new(Some(SOURCE_DOMAINS_HASHSET_CAP)),
+            origins: None,
+            records: None,
+            with_ips: false,
+            side_error: None,
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8]) {
+        for byte in chunk {
+            if *byte == b'\n' {
+                self.parse_line();
+            } else {
+                self.buf.push(*byte);
+            }
+        }
+    }
+
+    fn parse_line(&mut self) {
+        self.line_n += 1;
+        if self.buf.last() == Some(&b'\r') {
+            self.buf.pop();
+        }
+        let res = match self.format {
+            SourceFormat::ZapretCsv => self.parse_zapret_record(),
+            SourceFormat::Cidr => self.parse_cidr_line(),
+            _ => self.format.parse_line(&self.buf, &mut self.names),
+        };
+        match res {
+            Ok(_) => {
+                for name in self.names.drain(..) {
+                    if let Some(origins) = &mut self.origins {
+                        if let Err(e) = writeln!(origins, "{}\t{}", name, self.line_n) {
+                            self.side_error.get_or_insert(e);
+                        }
+                    }
+                    self.domains.insert(Domain::new(name));
+                }
+            },
+            Err(e) => {
+                if self.errors_count < MAX_LOGGED_ERRORS {
+                    warn!("Source '{}' line {}: {}", self.source_name, self.line_n, e);
+                } else {
+                    debug!("Source '{}' line {}: {}", self.source_name, self.line_n, e);
+                }
+                self.names.clear();
+                self.errors_count += 1;
+            }
+        }
+        self.buf.clear();
+    }
+
+    // Records are written to the index even without a valid domain,
+    // their addresses are blocked too
+    fn parse_zapret_record(&mut self) -> Result<(), String> {
+        let record = match ZapretRecord::parse(&self.buf)? {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        if let Some(records) = &mut self.records {
+            if let Err(e) = records.write(self.line_n, &record) {
+                self.side_error.get_or_insert(e);
+            }
+        }
+        if self.with_ips {
+            for ip in &record.ips {
+                self.domains.add_prefix(*ip);
+            }
+        }
+        // Records without domain are blocked by ip/url
+        if record.domain.is_empty() {
+            return Ok(())
+        }
+        push_domain(&record.domain, &mut self.names)
+    }
+
+    fn parse_cidr_line(&mut self) -> Result<(), String> {
+        let line = std::str::from_utf8(&self.buf).map_err(|e| e.to_string())?;
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            return Ok(())
+        }
+        let prefix = line.parse::<IpNet>()
+            .or_else(|_| line.parse::<IpAddr>().map(IpNet::from))
+            .map_err(|_| format!("invalid prefix '{}'", line))?;
+        self.domains.add_prefix(prefix.trunc());
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(Domains, u64), Box<dyn Error>> {
+        if ! self.buf.is_empty() {
+            self.parse_line();
+        }
+        if let Some(e) = self.side_error {
+            return Err(format!("Error while writing side files of the cache: {}", e).into())
+        }
+        if let Some(mut origins) = self.origins {
+            origins.flush()?;
+        }
+        if let Some(records) = self.records {
+            records.finish()?;
+        }
+        self.domains.build_regex_set()?;
+        Ok((self.domains, self.errors_count))
+    }
+}
+
+
+impl SourceFormat {
+    // Push domains from the line to `out`. Comments, empty lines and unsupported
+    // rules are skipped without error
+    fn parse_line(&self, line: &[u8], out: &mut Vec<String>) -> Result<(), String> {
+        if *self == SourceFormat::ZapretCsv {
+            return parse_zapret_csv_line(line, out)
+        }
+        let line = std::str::from_utf8(line).map_err(|e| e.to_string())?.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(())
+        }
+        match self {
+            SourceFormat::ZapretCsv => unreachable!(),
+            SourceFormat::Plain => push_domain(line, out),
+            SourceFormat::Hosts => parse_hosts_line(line, out),
+            SourceFormat::Dnsmasq => parse_dnsmasq_line(line, out),
+            SourceFormat::Adguard => parse_adguard_line(line, out),
+            SourceFormat::Geosite => Err(String::from("geosite is not a line based format")),
+            SourceFormat::Rpz => Err(String::from("rpz is not a line based format")),
+            SourceFormat::Cidr => Err(String::from("cidr lines have no domains")),
+        }
+    }
+}
+
+fn push_domain(domain: &str, out: &mut Vec<String>) -> Result<(), String> {
+    out.push(normalize_domain_name(domain)?);
+    Ok(())
+}
+
+fn parse_zapret_csv_line(line: &[u8], out: &mut Vec<String>) -> Result<(), String> {
+    match ZapretRecord::parse(line)? {
+        Some(record) if ! record.domain.is_empty() => push_domain(&record.domain, out),
+        // Records without domain are blocked by ip/url
+        _ => Ok(()),
+    }
+}
+
+fn parse_hosts_line(line: &str, out: &mut Vec<String>) -> Result<(), String> {
+    let line = line.split('#').next().unwrap_or_default();
+    let mut parts = line.split_whitespace();
+    if parts.next().is_none() {
+        return Ok(())
+    }
+    for name in parts {
+        if HOSTS_IGNORED_NAMES.contains(&name) {
+            continue
+        }
+        push_domain(name, out)?;
+    }
+    Ok(())
+}
+
+fn parse_dnsmasq_line(line: &str, out: &mut Vec<String>) -> Result<(), String> {
+    let value = match line.split_once('=') {
+        Some(("server" | "address" | "ipset" | "nftset" | "local", value)) => value,
+        _ => return Ok(()),
+    };
+    // "/domain1/domain2/upstream", the part after the last slash isn't a domain
+    let domains = match value.strip_prefix('/').and_then(|v| v.rsplit_once('/')) {
+        Some((domains, _)) => domains,
+        None => return Err(String::from("no domains")),
+    };
+    // dnsmasq matches the domain and all its subdomains
+    for domain in domains.split('/').filter(|d| !d.is_empty()) {
+        push_domain(&format!("*.{}", domain.trim_start_matches('.')), out)?;
+    }
+    Ok(())
+}
+
+fn parse_adguard_line(line: &str, out: &mut Vec<String>) -> Result<(), String> {
+    if line.starts_with('!') || line.starts_with('[') {
+        return Ok(())
+    }
+    let rule = match line.strip_prefix("||") {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    let rule = rule.split('$').next().unwrap_or_default();
+    let domain = match rule.strip_suffix('^').or_else(|| rule.strip_suffix("^|")) {
+        Some(d) => d,
+        None => return Ok(()),
+    };
+    if domain.contains('/') {
+        return Ok(())
+    }
+    // "||domain^" matches the domain and all its subdomains
+    push_domain(&format!("*.{}", domain.trim_start_matches("*.")), out)
+}
+
+// Empty string if the domain is invalid
+pub fn prepare_domain_name(domain: &str) -> String {
+    normalize_domain_name(domain).unwrap_or_default()
+}
+
+// Canonical lower-case ASCII (IDNA) form of the domain, queries always come
+// as punycode. "*." is allowed only as the first label
+pub fn normalize_domain_name(domain: &str) -> Result<String, String> {
+    if domain.contains("\\") || ! VALID_DOMAIN_RE.is_match(domain) {
+        return Err(format!("invalid domain '{}'", domain))
+    }
+    let name = domain.strip_suffix('.').unwrap_or(domain);
+    let (wildcard, name) = match name.strip_prefix("*.") {
+        Some(n) => ("*.", n),
+        None => ("", name),
+    };
+    if name.contains('*') {
+        return Err(format!("invalid wildcard in domain '{}'", domain))
+    }
+    let name = idna::Config::default()
+        .verify_dns_length(true)
+        .to_ascii(name)
+        .map_err(|e| format!("invalid domain '{}': {:?}", domain, e))?;
+    Ok(format!("{}{}", wildcard, name))
+}
+
+
+#[test]
+fn test_domain_source_parse_line() {
+    use encoding_rs::WINDOWS_1251;
+
+    fn parse(format: SourceFormat, line: &str) -> Result<Vec<String>, String> {
+        let mut out = vec![];
+        format.parse_line(line.as_bytes(), &mut out)?;
+        Ok(out)
+    }
+
+    let (csv_line, _, _) = WINDOWS_1251.encode("1.1.1.1;сайт.рф;http://сайт.рф/;Суд;2-1/2020;2020-01-01");
+    let mut out = vec![];
+    SourceFormat::ZapretCsv.parse_line(&csv_line, &mut out).unwrap();
+    assert_eq!(out, vec!["xn--80aswg.xn--p1ai"]);
+    assert_eq!(parse(SourceFormat::ZapretCsv, "Updated: 2024-01-01").unwrap(), Vec::<String>::new());
+    assert_eq!(parse(SourceFormat::ZapretCsv, "1.1.1.1;;;org;1;2020").unwrap(), Vec::<String>::new());
+
+    assert_eq!(parse(SourceFormat::Plain, "some.domain.ru.").unwrap(), vec!["some.domain.ru"]);
+    assert_eq!(parse(SourceFormat::Plain, "Some.Domain.RU").unwrap(), vec!["some.domain.ru"]);
+    assert_eq!(parse(SourceFormat::Plain, "*.Пример.РФ").unwrap(), vec!["*.xn--e1afmkfd.xn--p1ai"]);
+    assert!(parse(SourceFormat::Plain, "a..ru").is_err());
+    assert!(parse(SourceFormat::Plain, "a.*.ru").is_err());
+    assert_eq!(parse(SourceFormat::Plain, "# comment").unwrap(), Vec::<String>::new());
+    assert!(parse(SourceFormat::Plain, "bad domain").is_err());
+
+    assert_eq!(
+        parse(SourceFormat::Hosts, "0.0.0.0 a.com b.com # ads").unwrap(),
+        vec!["a.com", "b.com"],
+    );
+    assert_eq!(parse(SourceFormat::Hosts, "127.0.0.1 localhost").unwrap(), Vec::<String>::new());
+
+    assert_eq!(
+        parse(SourceFormat::Dnsmasq, "server=/a.com/b.org/127.0.0.1#5353").unwrap(),
+        vec!["*.a.com", "*.b.org"],
+    );
+    assert_eq!(parse(SourceFormat::Dnsmasq, "ipset=/c.net/vpn").unwrap(), vec!["*.c.net"]);
+    assert_eq!(parse(SourceFormat::Dnsmasq, "cache-size=1000").unwrap(), Vec::<String>::new());
+
+    assert_eq!(parse(SourceFormat::Adguard, "||ads.com^").unwrap(), vec!["*.ads.com"]);
+    assert_eq!(parse(SourceFormat::Adguard, "||ads.com^$important").unwrap(), vec!["*.ads.com"]);
+    assert_eq!(parse(SourceFormat::Adguard, "! comment").unwrap(), Vec::<String>::new());
+    assert_eq!(parse(SourceFormat::Adguard, "example.org##.banner").unwrap(), Vec::<String>::new());
+}
+
+
+#[test]
+fn test_domain_source_guards() {
+    let guards = SourceGuards {
+        min_entries: 10,
+        max_shrink_percent: Some(50.0),
+        max_error_ratio: Some(0.1),
+    };
+    assert!(guards.check(100, 0, None).is_ok());
+    assert!(guards.check(100, 5, Some(150)).is_ok());
+    assert!(guards.check(5, 0, None).is_err());
+    assert!(guards.check(40, 0, Some(100)).is_err());
+    assert!(guards.check(100, 20, Some(100)).is_err());
+    assert!(SourceGuards::default().check(0, 0, None).is_err());
+    assert!(SourceGuards::new(SourceRole::Exclude).check(0, 0, None).is_ok());
+}
+
+#[tokio::test]
+async fn test_domain_source_mirror_failover() {
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mirror = Url::parse(&format!("http://{}/list.txt", listener.local_addr().unwrap())).unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let body = "a.com\nb.com\n";
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(), body,
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+
+    let workdir = tempfile::tempdir().unwrap();
+    // Nothing listens on the port of the closed listener
+    let dead_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut source = DomainSource::new(
+        "mirrored",
+        SourceLocation::Url(Url::parse(&format!("http://{}/list.txt", dead_addr)).unwrap()),
+        SourceFormat::Plain,
+        SourceRole::Include,
+    );
+    source.mirrors.push(mirror);
+    let domains = source.refresh(workdir.path(), &ListFetcher::default()).await.unwrap();
+    assert_eq!(domains.count(), 2);
+}
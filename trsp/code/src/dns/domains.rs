@@ -8,9 +8,13 @@ use tokio::{
 };
 use std::fmt::{self, Display};
 use std::ops::{Deref, DerefMut};
+use ipnet::IpNet;
+
+use super::patterns::{Patterns, EXCEPTION_PREFIX, KEYWORD_PREFIX, REGEX_PREFIX};
 
 
 const DEFAULT_DOMAINS_HASHSET_CAP: usize = 2_000_000;
+const PREFIX_PREFIX: &str = "ip:";
 // const DEFAULT_NXDOMAINS_HASHSET_CAP: usize = 500_000;
 
 
@@ -70,9 +74,16 @@ impl Domain {
 }
 
 
+// Exact names and "*." wildcards are stored in the HashSet,
+// keyword (substring) and regex rules are checked one by one.
+// IP prefixes of the source are checked against resolved addresses.
+// Exceptions ("@@" in the cache file) are names which are not blocked
 #[derive(Debug)]
 pub struct Domains {
     domains: HashSet<Domain>,
+    exceptions: HashSet<Domain>,
+    patterns: Patterns,
+    prefixes: Vec<IpNet>,
 }
 
 impl Deref for Domains {
@@ -96,6 +107,9 @@ impl Domains {
     pub fn new(hashset_cap: Option<usize>) -> Self {
         Self {
             domains: HashSet::with_capacity(hashset_cap.or(Some(DEFAULT_DOMAINS_HASHSET_CAP)).unwrap()),
+            exceptions: HashSet::new(),
+            patterns: Patterns::new(),
+            prefixes: vec![],
         }
     }
 
@@ -119,19 +133,92 @@ impl Domains {
         self.domains.insert(Domain::new(domain))
     }
 
+    pub fn exceptions(&self) -> impl Iterator<Item = &Domain> {
+        self.exceptions.iter()
+    }
+
+    pub fn get_exception(&self, domain: &str) -> Option<&Domain> {
+        self.exceptions.get(domain)
+    }
+
+    pub fn add_exception(&mut self, domain: &str) -> bool {
+        self.exceptions.insert(Domain::new(domain))
+    }
+
+    pub fn remove_exception(&mut self, domain: &str) -> bool {
+        self.exceptions.remove(domain)
+    }
+
+    pub fn patterns_count(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn prefixes(&self) -> &[IpNet] {
+        &self.prefixes
+    }
+
+    pub fn add_prefix(&mut self, prefix: IpNet) {
+        self.prefixes.push(prefix);
+    }
+
+    // Names, exceptions, patterns and prefixes
+    pub fn entries_count(&self) -> usize {
+        self.count() + self.exceptions.len() + self.patterns_count() + self.prefixes.len()
+    }
+
+    pub fn add_keyword(&mut self, keyword: &str) {
+        self.patterns.add_keyword(keyword);
+    }
+
+    // Regex set must be rebuilt with build_regex_set() after adding
+    pub fn add_regex(&mut self, regex: &str) -> Result<(), regex::Error> {
+        self.patterns.add_regex(regex)?;
+        Ok(())
+    }
+
+    // On error regex rules are dropped, keyword rules still match
+    pub fn build_regex_set(&mut self) -> Result<(), String> {
+        self.patterns.build()
+    }
+
+    // Check name against keyword and regex rules
+    pub fn is_match_patterns(&self, name: &str) -> bool {
+        self.patterns.is_match(name)
+    }
+
+    // The first keyword or regex rule matching the name, as in the cache file
+    pub fn matching_pattern(&self, name: &str) -> Option<String> {
+        self.patterns.find(name).map(|p| self.patterns.rule(p))
+    }
+
+    pub fn clear(&mut self) {
+        self.domains.clear();
+        self.exceptions.clear();
+        self.patterns.clear();
+        self.prefixes.clear();
+    }
+
     pub async fn write_to_file(&self, write_to: &PathBuf) -> Result<(), Box<dyn Error>> {
         // Write to file 100 domains per operation
         let mut file = File::create(write_to).await?;
         let mut buf: Vec<&str> = Vec::with_capacity(100);
         for domain in &self.domains {
             if buf.len() == 100 {
-                file.write(buf.join("\n").as_bytes()).await?;
+                file.write_all((buf.join("\n") + "\n").as_bytes()).await?;
                 buf.clear();
             }
             buf.push(domain.as_str());
         }
-        if buf.len() != 0 {
-            file.write(buf.join("\n").as_bytes()).await?;
+        let exceptions: Vec<String> = self.exceptions.iter().map(|e| format!("{}{}", EXCEPTION_PREFIX, e)).collect();
+        buf.extend(exceptions.iter().map(|e| e.as_str()));
+        let keywords: Vec<String> = self.patterns.keywords().iter().map(|k| format!("{}{}", KEYWORD_PREFIX, k)).collect();
+        let regexes: Vec<String> = self.patterns.regexes().iter().map(|r| format!("{}{}", REGEX_PREFIX, r)).collect();
+        buf.extend(keywords.iter().map(|k| k.as_str()));
+        buf.extend(regexes.iter().map(|r| r.as_str()));
+        let prefixes: Vec<String> = self.prefixes.iter().map(|p| format!("{}{}", PREFIX_PREFIX, p)).collect();
+        buf.extend(prefixes.iter().map(|p| p.as_str()));
+        if ! buf.is_empty() {
+            file.write_all((buf.join("\n") + "\n").as_bytes()).await?;
         }
         file.flush().await?;
         Ok(())
@@ -143,16 +230,34 @@ impl Domains {
         let mut lines = reader.lines();
 
         while let Some(line) = lines.next_line().await? {
-            self.domains.insert(Domain::new(line));
+            if line.is_empty() {
+                continue
+            }
+            if let Some(exception) = line.strip_prefix(EXCEPTION_PREFIX) {
+                self.exceptions.insert(Domain::new(exception));
+            } else if let Some(keyword) = line.strip_prefix(KEYWORD_PREFIX) {
+                self.add_keyword(keyword);
+            } else if let Some(regex) = line.strip_prefix(REGEX_PREFIX) {
+                self.add_regex(regex)?;
+            } else if let Some(prefix) = line.strip_prefix(PREFIX_PREFIX) {
+                self.prefixes.push(prefix.parse()?);
+            } else {
+                self.domains.insert(Domain::new(line));
+            }
         }
+        self.build_regex_set()?;
         Ok(())
     }
 
-    fn extend(&mut self, domains: HashSet<Domain>) {
-        self.domains.extend(domains);
+    pub fn extend(&mut self, domains: Domains) -> Result<(), String> {
+        self.domains.extend(domains.domains);
+        self.exceptions.extend(domains.exceptions);
+        self.prefixes.extend(domains.prefixes);
+        self.patterns.extend(domains.patterns)
     }
 
     pub fn cleanup(&mut self) {
+        self.clear();
         self.domains = HashSet::with_capacity(DEFAULT_DOMAINS_HASHSET_CAP);
     }
 }
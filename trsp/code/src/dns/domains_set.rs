@@ -1,18 +1,24 @@
 use std::{
+    collections::HashSet,
     error::Error,
+    fmt::{self, Display},
+    net::IpAddr,
     path::{Path, PathBuf},
     sync::Arc,
-    env,
     fs,
     time::Instant,
 };
-use tracing::{debug, info, error, warn};
-use tokio_stream::StreamExt;
-use reqwest::Url;
-use encoding_rs::WINDOWS_1251;
-use lazy_static::lazy_static;
-use regex::Regex;
-use super::domains::{Domains, Domain};
+use arc_swap::ArcSwap;
+use tracing::{error, info, warn};
+use super::domains::Domains;
+use super::domain_rules::DomainRules;
+use super::explain::Explanation;
+use super::compact_domains::CompactDomains;
+use super::prefix_set::PrefixSet;
+use super::geoip::GeoIp;
+use super::local_records::LocalRecords;
+use super::domain_source::{normalize_domain_name, DomainSource, SourceRole};
+use super::list_fetcher::ListFetcher;
 use tokio::{
     io::{BufReader, AsyncBufReadExt, BufWriter, AsyncWriteExt},
     sync::RwLock,
@@ -20,68 +26,118 @@ use tokio::{
 };
 
 
-const ZAPRET_DOMAINS_TMP_FILENAME: &str = "_trsp_zapret_domains";
-const ZAPRET_NXDOMAINS_TMP_FILENAME: &str = "_trsp_zapret_nxdomains";
-const INCLUDED_DOMAINS_FILENAME: &str = "included_domains.txt";
-const EXCLUDED_DOMAINS_FILENAME: &str = "excluded_domains.txt";
+pub const INCLUDED_DOMAINS_FILENAME: &str = "included_domains.txt";
+pub const EXCLUDED_DOMAINS_FILENAME: &str = "excluded_domains.txt";
+const IMPORTED_DOMAINS_FST_FILENAME: &str = "_trsp_imported_domains.fst";
 
 
-lazy_static! {
-    static ref VALID_DOMAIN_RE: Regex = Regex::new(r"^[а-яА-Яa-zA-Z0-9\-_\.\*]*+$").unwrap();
-}
-
-
-pub type ArcDomainsSet = Arc<DomainsSet>;
+// The set is replaced as a whole on reload, lookups always see either
+// the old or the new one
+pub type ArcDomainsSet = Arc<ArcSwap<DomainsSet>>;
 
+// Precedence: excluded rules, then included rules, then imported domains.
+// "@@" exceptions only cancel matches of rules of their own list, exceptions
+// of sources cancel matches of all imported domains.
+// Names that are not blocked are still blocked by addresses of their
+// answers in the imported prefixes or matching GeoIP rules
 pub struct DomainsSet {
-    pub included_domains: RwLock<Domains>,
-    pub excluded_domains: RwLock<Domains>,
-    pub imported_domains: RwLock<Domains>,
+    pub included_domains: RwLock<DomainRules>,
+    pub excluded_domains: RwLock<DomainRules>,
+    pub imported_domains: RwLock<CompactDomains>,
+    pub imported_prefixes: RwLock<PrefixSet>,
+    // ASN and country rules of the config
+    pub geoip: Option<Arc<GeoIp>>,
+    // Answered instead of forwarding, before the domains are checked
+    pub local_records: Arc<LocalRecords>,
     pub workdir: PathBuf,
-    pub zapret_domains_csv_url: Option<Url>,
-    pub zapret_nxdomains_txt_url: Option<Url>,
+    // Map the imported domains from the file instead of keeping them in memory
+    pub mmap_imported_domains: bool,
+    pub sources: Vec<DomainSource>,
+    pub fetcher: Arc<ListFetcher>,
 }
 
 #[allow(dead_code)]
 impl DomainsSet {
     pub fn new(workdir: &PathBuf) -> Self {
         DomainsSet {
-            included_domains: RwLock::new(Domains::new(None)),
-            excluded_domains: RwLock::new(Domains::new(None)),
-            imported_domains: RwLock::new(Domains::new(None)),
+            included_domains: RwLock::new(DomainRules::new()),
+            excluded_domains: RwLock::new(DomainRules::new()),
+            imported_domains: RwLock::new(CompactDomains::empty()),
+            imported_prefixes: RwLock::new(PrefixSet::new()),
+            geoip: None,
+            local_records: Arc::new(LocalRecords::new()),
             workdir: workdir.clone(),
-            zapret_domains_csv_url: None,
-            zapret_nxdomains_txt_url: None,
+            mmap_imported_domains: false,
+            sources: vec![],
+            fetcher: Arc::new(ListFetcher::default()),
         }
     }
 
     pub async fn clear(&self) {
-        self.included_domains.write().await.clear();
-        self.excluded_domains.write().await.clear();
-        self.imported_domains.write().await.clear();
+        *self.included_domains.write().await = DomainRules::new();
+        *self.excluded_domains.write().await = DomainRules::new();
+        *self.imported_domains.write().await = CompactDomains::empty();
+        *self.imported_prefixes.write().await = PrefixSet::new();
     }
 
     pub async fn is_domain_blocked(&self, name: &str) -> bool {
         let name = name.trim_end_matches(".");
-        if DomainsSet::is_domain_in_domains(name, &self.excluded_domains).await  {
+        if self.excluded_domains.read().await.is_match(name) {
             return false
         }
-        if DomainsSet::is_domain_in_domains(name, &self.included_domains).await  {
+        if self.included_domains.read().await.is_match(name) {
             return true
         }
-        if DomainsSet::is_domain_in_domains(name, &self.imported_domains).await  {
+        if self.imported_domains.read().await.contains(name) {
             return true
         }
         false
     }
 
+    pub async fn is_addr_blocked(&self, addr: &IpAddr) -> bool {
+        self.addr_block_reason(addr).await.is_some()
+    }
+
+    // "prefix" or the matched GeoIP rule, None if the address isn't blocked
+    pub async fn addr_block_reason(&self, addr: &IpAddr) -> Option<String> {
+        if self.imported_prefixes.read().await.contains(addr) {
+            return Some(String::from("prefix"))
+        }
+        self.geoip.as_ref().and_then(|g| g.find(*addr))
+    }
+
+    // Rules of the lists and sources matching the name. Sources are read
+    // from their cache files, so it's slow and only for troubleshooting
+    pub async fn explain(&self, name: &str) -> Explanation {
+        let name = name.trim_end_matches('.');
+        let name = normalize_domain_name(name).unwrap_or_else(|_| name.to_lowercase());
+        let mut matches = self.excluded_domains.read().await.explain(&name, EXCLUDED_DOMAINS_FILENAME, SourceRole::Exclude);
+        matches.extend(self.included_domains.read().await.explain(&name, INCLUDED_DOMAINS_FILENAME, SourceRole::Include));
+        let mut err: Vec<String> = vec![];
+        for source in &self.sources {
+            match source.explain(&self.workdir, &name).await {
+                Ok(m) => matches.extend(m),
+                Err(e) => err.push(format!("Source '{}': {}", source.name, e)),
+            }
+        }
+        let mut explanation = Explanation::new(&name, self.is_domain_blocked(&name).await, matches);
+        explanation.errors = err;
+        explanation
+    }
+
     pub async fn add_blocked_domain(&mut self, domain: &str) {
-        self.included_domains.write().await.insert(Domain::new(domain));
+        let mut included_domains = self.included_domains.write().await;
+        if let Err(e) = included_domains.add(domain).and_then(|_| included_domains.build()) {
+            error!("Included rule '{}': {}", domain, e);
+        }
         // TODO Write it to file
     }
 
     pub async fn add_excluded_domain(&mut self, domain: &str) {
-        self.excluded_domains.write().await.insert(Domain::new(domain));
+        let mut excluded_domains = self.excluded_domains.write().await;
+        if let Err(e) = excluded_domains.add(domain).and_then(|_| excluded_domains.build()) {
+            error!("Excluded rule '{}': {}", domain, e);
+        }
         // TODO Write it to file
     }
 
@@ -91,26 +147,6 @@ impl DomainsSet {
         s
     }
 
-    async fn is_domain_in_domains(name: &str, domains: &RwLock<Domains>) -> bool {
-        let domains = domains.read().await;
-        if let Some(_) = domains.get(name) {
-            return true
-        }
-        let mut domain_incr = String::new();
-        let inversed_name = DomainsSet::reverse_domain(name);
-        for dpart in inversed_name.split(".") {
-            domain_incr = format!("{}.{}", domain_incr, dpart);
-            let search_string: String = format!(
-                "*.{}",
-                DomainsSet::reverse_domain(domain_incr.as_str()),
-            ).trim_end_matches(".").into();
-            if let Some(_) = domains.get(search_string.as_str()) {
-                return true
-            }
-        }
-        false
-    }
-
     pub async fn save_included_domains(&self) -> Result<(), Box<dyn Error>> {
         // TODO: Tests
         let filepath = self.workdir.join(INCLUDED_DOMAINS_FILENAME);
@@ -126,14 +162,16 @@ impl DomainsSet {
     async fn write_txt_domains_file(
         &self,
         filepath: &PathBuf,
-        domains: &RwLock<Domains>
+        domains: &RwLock<DomainRules>
     ) -> Result<(), Box<dyn Error>>
     {
-        let file = File::open(filepath).await?;
+        let file = File::create(filepath).await?;
         let mut bufwriter = BufWriter::new(file);
-        for domain in domains.read().await.iter() {
-            bufwriter.write(domain.as_str().as_bytes()).await?;
+        for line in domains.read().await.lines() {
+            bufwriter.write_all(line.as_bytes()).await?;
+            bufwriter.write_all(b"\n").await?;
         }
+        bufwriter.flush().await?;
         Ok(())
     }
 
@@ -141,6 +179,7 @@ impl DomainsSet {
         // TODO: Tests
         let filepath = self.workdir.join(INCLUDED_DOMAINS_FILENAME);
         let domains = self.read_txt_domains_file(&filepath).await?;
+        info!("Loaded {} included rules", domains.len());
         let mut included_domains = self.included_domains.write().await;
         *included_domains = domains;
         Ok(())
@@ -150,242 +189,175 @@ impl DomainsSet {
         // TODO: Tests
         let filepath = self.workdir.join(EXCLUDED_DOMAINS_FILENAME);
         let domains = self.read_txt_domains_file(&filepath).await?;
+        info!("Loaded {} excluded rules", domains.len());
         let mut excluded_domains = self.excluded_domains.write().await;
         *excluded_domains = domains;
         Ok(())
     }
 
-    async fn read_txt_domains_file(&self, filepath: &PathBuf) -> Result<Domains, Box<dyn Error>>  {
-        let mut domains = Domains::new(None);
+    // Invalid rules are skipped with a warning, the rest of the file is loaded
+    async fn read_txt_domains_file(&self, filepath: &PathBuf) -> Result<DomainRules, Box<dyn Error>>  {
+        let mut domains = DomainRules::new();
         let path = Path::new(filepath);
         if ! path.exists() {
             fs::File::create(path)?;
-            return Ok(Domains::new(None))
+            return Ok(domains)
         }
         let file = File::open(path).await?;
         let mut lines = BufReader::new(file).lines();
+        let mut line_number = 0;
         while let Some(line) = lines.next_line().await? {
-            let domain = Domain::new(line);
-            domains.insert(domain);
+            line_number += 1;
+            if let Err(e) = domains.add_line(&line, Some(line_number)) {
+                warn!("{}:{}: invalid rule '{}': {}", filepath.display(), line_number, line, e);
+            }
+        }
+        if let Err(e) = domains.build() {
+            error!("{}: {}", filepath.display(), e);
         }
         Ok(domains)
     }
 
-    pub async fn import_domains(&self) -> Result<(), Box<dyn Error>> {
-        let mut err: Vec<String> = vec![];
+    pub async fn import_domains(&self) -> Result<ImportReport, Box<dyn Error>> {
+        self.import(false).await
+    }
+
+    // Sources are not fetched, they are loaded from their cache files
+    pub async fn import_cached_domains(&self) -> Result<ImportReport, Box<dyn Error>> {
+        self.import(true).await
+    }
+
+    // Only an error of building the imported domains fails the import.
+    // Failed sources are reported and imported from their cache files,
+    // sources without the cache don't contribute anything
+    async fn import(&self, cached: bool) -> Result<ImportReport, Box<dyn Error>> {
+        let mut report = ImportReport::default();
         if let Err(e) = self.load_included_domains().await {
-            err.push(e.to_string());
+            report.errors.push(e.to_string());
         }
         if let Err(e) = self.load_excluded_domains().await {
-            err.push(e.to_string());
+            report.errors.push(e.to_string());
         }
-        let tmp_dir = env::temp_dir();
-        debug!("Temp dir for downloads is {}", tmp_dir.as_path().display());
 
         let start = Instant::now();
-        self.download_zapret_csv_domains().await?;
-        if let Err(e) = self.download_zapret_csv_domains().await {
-            err.push(e.to_string());
-        }
-        let duration = start.elapsed();
-        warn!("Domains load time: {:?}", duration);
-
-        if let Some(_) = &self.zapret_nxdomains_txt_url {
-            let start = Instant::now();
-            self.download_zapret_nxdomains().await?;
-            if let Err(e) = self.download_zapret_nxdomains().await {
-                err.push(e.to_string());
-            }
-            let duration = start.elapsed();
-            warn!("NXDomains load time: {:?}", duration);
-        }
-        if ! err.is_empty() {
-            return Err(err.join(". ").into())
-        }
-        return Ok(())
-    }
-
-    async fn load_domains_from_cache(
-        &self,
-        domains: &mut Domains,
-        cache_filepath: &PathBuf
-    )  -> Result<(), Box<dyn Error>>
-    {
-        warn!(
-            "Load domains from cache file: {}",
-            &cache_filepath.as_path().display(),
-        );
-        return domains.read_from_file(cache_filepath).await
-
-    }
-
-    // Update self.domains from downloaded csv list
-    async fn download_zapret_csv_domains(&self) -> Result<(), Box<dyn Error>> {
-
-        let url = if let Some(u) = &self.zapret_domains_csv_url {
-            u
-        } else {
-            return Err("No zapret_domains_csv_url".into())
-        };
-        let cache_filepath = self.workdir.join(ZAPRET_DOMAINS_TMP_FILENAME);
-
-        let response = match reqwest::get(url.clone()).await {
-            Ok(r) => r,
-            Err(err) => {
-                error!("Load domains error: {}", err);
-                let mut domains = self.imported_domains.write().await;
-                return self.load_domains_from_cache(&mut *domains, &cache_filepath).await
-            }
-        };
-
-        match response.error_for_status_ref() {
-            Ok(_) => debug!("Reqwest get OK: {} {}", url, response.status()),
-            Err(err) => {
-                error!("Load domains error: {}", err);
-                let mut domains = self.imported_domains.write().await;
-                return self.load_domains_from_cache(&mut *domains, &cache_filepath).await
-            }
-        };
-
-        let mut errors_count: u64 = 0;
-        // The num of column that contains the domain
-        let domain_column_num = 2;
-        // Buffer for domain str
-        let mut buf: Vec<u8> = Vec::with_capacity(100);
-        // Column in csv line
-        let mut current_column: u8 = 1;
-        let mut line_n: u64 = 0;
-        let mut stream = response.bytes_stream();
-        let mut imported_domains = self.imported_domains.write().await;
-        while let Some(chunk) = stream.next().await {
-            for byte in chunk? {
-                if byte == b';' {
-                    current_column += 1;
-                    continue;
-                } else if current_column == domain_column_num {
-                    buf.push(byte);
-                    continue
-                } else if byte == b'\n' {
-                    // Reset csv column to first
-                    current_column = 1;
-                    line_n += 1;
-                    let domain_buf = buf.clone();
-                    buf.clear();
-                    if let Ok(domain_s) = prepare_csv_domain(&domain_buf) {
-                        if domain_s.len() != 0 && !imported_domains.contains(&domain_s) {
-                            imported_domains.insert(Domain::new(domain_s));
-                        }
-                        continue
-                    } else {
-                        debug!("Error while parsing domains csv at line {}", line_n);
-                        errors_count += 1;
+        // Imported names are collected as reversed keys of CompactDomains,
+        // source hashsets are dropped as soon as they are converted
+        let mut imported_keys: Vec<String> = vec![];
+        let mut imported_exception_keys: Vec<String> = vec![];
+        let mut imported_patterns = Domains::new(Some(0));
+        let mut excluded_keys: HashSet<String> = HashSet::new();
+        let mut imported_prefixes = vec![];
+        for source in &self.sources {
+            let res = if cached {
+                source.load_cache(&self.workdir).await.map(|d| (d, None))
+            } else {
+                source.load(&self.workdir, &self.fetcher).await
+            };
+            let mut domains = match res {
+                Ok((d, refresh_error)) => {
+                    if let Some(e) = refresh_error {
+                        report.add_source_error(&source.name, e);
                     }
+                    d
+                },
+                Err(e) => {
+                    report.add_source_error(&source.name, e.to_string());
+                    continue
                 }
+            };
+            match source.role {
+                SourceRole::Include => {
+                    imported_prefixes.extend(domains.prefixes().iter().copied());
+                    imported_exception_keys.extend(domains.exceptions().map(|d| CompactDomains::reverse_name(d.as_str())));
+                    imported_keys.extend(domains.drain().map(|d| CompactDomains::reverse_name(d.as_str())));
+                    if let Err(e) = imported_patterns.extend(domains) {
+                        report.add_source_error(&source.name, e.to_string());
+                    }
+                },
+                SourceRole::Exclude => {
+                    if domains.patterns_count() != 0 {
+                        warn!(
+                            "Source '{}': keyword and regex rules can't be excluded, skip them",
+                            source.name,
+                        );
+                    }
+                    if ! domains.prefixes().is_empty() {
+                        warn!("Source '{}': prefixes can't be excluded, skip them", source.name);
+                    }
+                    if domains.exceptions().next().is_some() {
+                        warn!("Source '{}': exceptions can't be excluded, skip them", source.name);
+                    }
+                    excluded_keys.extend(domains.drain().map(|d| CompactDomains::reverse_name(d.as_str())));
+                },
             }
         }
-        imported_domains.write_to_file(&cache_filepath).await?;
+        // Exclude sources are applied after all include sources. As "*.x"
+        // also matches "x", an excluded "x" or "*.x" removes both "x" and
+        // "*.x", and an excluded "*.x" removes "+.x" (subdomains only) too.
+        // An excluded "+.x" removes "+.x" only
+        imported_keys.retain(|k| {
+            let other_key = match (k.strip_suffix(".*"), k.strip_suffix(".+")) {
+                (Some(k), _) => String::from(k),
+                (_, Some(k)) => format!("{}.*", k),
+                _ => format!("{}.*", k),
+            };
+            !excluded_keys.contains(k) && !excluded_keys.contains(&other_key)
+        });
+        drop(excluded_keys);
+        let mmap_filepath = self.workdir.join(IMPORTED_DOMAINS_FST_FILENAME);
+        let mmap_filepath = match self.mmap_imported_domains {
+            true => Some(&mmap_filepath),
+            false => None,
+        };
+        let imported_domains = CompactDomains::build(imported_keys, imported_exception_keys, imported_patterns, mmap_filepath)
+            .map_err(|e| format!("Error while building imported domains: {}", e))?;
         info!(
-            "Download domains csv file completed {}, errors: {}",
-            cache_filepath.as_path().display(),
-            errors_count,
+            "Imported {} domains and {} patterns",
+            imported_domains.count(), imported_domains.patterns_count(),
         );
-        Ok(())
-    }
+        *self.imported_domains.write().await = imported_domains;
+        let prefixes = PrefixSet::build(&imported_prefixes);
+        info!("Imported {} prefixes ({} ranges)", imported_prefixes.len(), prefixes.len());
+        *self.imported_prefixes.write().await = prefixes;
+        warn!("Domains load time: {:?}", start.elapsed());
 
-    async fn remove_nx_domains_from_imported(&self, nx_domains: &Domains) -> Result<(), Box<dyn Error>> {
-        let mut imported_domains = self.imported_domains.write().await;
-        for domain in nx_domains.iter() {
-            imported_domains.remove(domain.as_str());
-        }
-        Ok(())
+        Ok(report)
     }
+}
 
-    pub async fn download_zapret_nxdomains(&self ) -> Result<(), Box<dyn Error>> {
-        let mut cached_domains = Domains::new(None);
-        let cache_filepath = self.workdir.join(ZAPRET_NXDOMAINS_TMP_FILENAME);
-
-        let url = if let Some(u) = &self.zapret_nxdomains_txt_url {
-            u
-        } else {
-            return Err("No zapret_nxdomains_txt_url".into())
-        };
-
-
-        let response = match reqwest::get(url.clone()).await {
-            Ok(r) => r,
-            Err(err) => {
-                error!("Load nxdomains error: {}", err);
-                self.load_domains_from_cache(&mut cached_domains, &cache_filepath).await?;
-                return self.remove_nx_domains_from_imported(&cached_domains).await
-            }
-        };
-
-        match response.error_for_status_ref() {
-            Ok(_) => debug!("Reqwest get OK: {} {}", url, response.status()),
-            Err(err) => {
-                error!("Load nxdomains error: {}", err);
-                warn!(
-                    "Load nxdomains from cache file: {}",
-                    &cache_filepath.as_path().display()
-                );
-                self.load_domains_from_cache(&mut cached_domains, &cache_filepath).await?;
-                return self.remove_nx_domains_from_imported(&cached_domains).await
-            }
-        };
 
-        let mut buf: Vec<u8> = Vec::with_capacity(100);
-        let mut stream = response.bytes_stream();
-        while let Some(chunk) = stream.next().await {
-            for byte in chunk? {
-                if byte == b'\n' {
-                    let line = String::from_utf8(buf.clone())?;
-                    let domain_s = prepare_domain_name(&line);
-                    cached_domains.insert(Domain::new(domain_s));
-                    buf.clear();
-                } else {
-                    buf.push(byte);
-                }
-            }
-        }
+// Errors which don't fail the import
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    // Included and excluded rules files
+    pub errors: Vec<String>,
+    // Source name and error
+    pub source_errors: Vec<(String, String)>,
+}
 
-        self.remove_nx_domains_from_imported(&cached_domains).await?;
-        let imported_domains = self.imported_domains.write().await;
-        imported_domains.write_to_file(&cache_filepath).await?;
-        info!(
-            "Download nxdomains txt file completed: {}",
-            cache_filepath.as_path().display(),
-        );
-        Ok(())
+impl ImportReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty() && self.source_errors.is_empty()
     }
-}
 
+    pub fn source_error(&self, name: &str) -> Option<&str> {
+        self.source_errors.iter().find(|(n, _)| n == name).map(|(_, e)| e.as_str())
+    }
 
-fn prepare_csv_domain(buf: &Vec<u8>) -> Result<String, Box<dyn Error>> {
-    let (enc_res, _, had_errors) = WINDOWS_1251.decode(&buf);
-    if had_errors {
-        return Err("Error while parsing csv domain from cp1251".into());
+    fn add_source_error(&mut self, name: &str, error: String) {
+        self.source_errors.push((String::from(name), error));
     }
-    let domain = String::from(enc_res);
-    Ok(prepare_domain_name(&domain))
 }
 
-fn prepare_domain_name(domain: &String) -> String {
-    if domain.contains("\\") {
-        return "".into()
+impl Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut err = self.errors.clone();
+        err.extend(self.source_errors.iter().map(|(n, e)| format!("Source '{}': {}", n, e)));
+        write!(f, "{}", err.join(". "))
     }
-    if ! VALID_DOMAIN_RE.is_match(domain.as_ref()) {
-        return "".into()
-    }
-    // let domain = domain.replace("*.", "");
-    let domain = match domain.strip_suffix(".") {
-        Some(s) => String::from(s),
-        None => String::from(domain),
-    };
-    domain
 }
 
 
-
 // impl IntoIterator for Domains {
 //     type Item = String;
 //     type IntoIter = <HashSet<String> as IntoIterator>::IntoIter;
@@ -516,3 +488,145 @@ async fn test_domains_set_is_domain_in_domains() -> Result<(), String> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_domains_set_import_domains_from_sources() {
+    use super::domain_source::{SourceFormat, SourceLocation};
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let workdir = tempdir.path().to_path_buf();
+    let included_path = workdir.join("included_source.txt");
+    let excluded_path = workdir.join("excluded_source.txt");
+    fs::write(&included_path, "0.0.0.0 blocked.ru nx.ru\n0.0.0.0 another.ru\n").unwrap();
+    fs::write(&excluded_path, "nx.ru\nwild.ru\n").unwrap();
+    let wildcard_path = workdir.join("wildcard_source.txt");
+    fs::write(&wildcard_path, "*.wild.ru\n").unwrap();
+    let empty_excluded_path = workdir.join("empty_excluded_source.txt");
+    fs::write(&empty_excluded_path, "").unwrap();
+    let cidr_path = workdir.join("cidr_source.txt");
+    fs::write(&cidr_path, "# subnets\n10.1.0.0/16\n192.0.2.7\n2001:db8::/32\n").unwrap();
+
+    let mut domains_set = DomainsSet::new(&workdir);
+    domains_set.sources = vec![
+        DomainSource::new(
+            "included",
+            SourceLocation::Path(included_path.clone()),
+            SourceFormat::Hosts,
+            SourceRole::Include,
+        ),
+        DomainSource::new(
+            "excluded",
+            SourceLocation::Path(excluded_path),
+            SourceFormat::Plain,
+            SourceRole::Exclude,
+        ),
+        DomainSource::new(
+            "cidr",
+            SourceLocation::Path(cidr_path),
+            SourceFormat::Cidr,
+            SourceRole::Include,
+        ),
+        DomainSource::new(
+            "wildcard",
+            SourceLocation::Path(wildcard_path),
+            SourceFormat::Plain,
+            SourceRole::Include,
+        ),
+        // Empty exclude list passes the guards
+        DomainSource::new(
+            "empty_excluded",
+            SourceLocation::Path(empty_excluded_path),
+            SourceFormat::Plain,
+            SourceRole::Exclude,
+        ),
+    ];
+    assert!(domains_set.import_domains().await.unwrap().is_ok());
+    assert!(domains_set.is_addr_blocked(&"10.1.2.3".parse().unwrap()).await);
+    assert!(domains_set.is_addr_blocked(&"192.0.2.7".parse().unwrap()).await);
+    assert!(domains_set.is_addr_blocked(&"2001:db8::1".parse().unwrap()).await);
+    assert!(!domains_set.is_addr_blocked(&"10.2.0.1".parse().unwrap()).await);
+    assert!(domains_set.is_domain_blocked("blocked.ru.").await);
+    assert!(domains_set.is_domain_blocked("another.ru").await);
+    assert!(!domains_set.is_domain_blocked("nx.ru").await);
+    // Excluded name removes its wildcard
+    assert!(!domains_set.is_domain_blocked("sub.wild.ru").await);
+    let explanation = domains_set.explain("sub.wild.ru").await;
+    assert!(!explanation.is_blocked);
+    assert!(explanation.reason.contains("removed by source 'excluded'"));
+
+    let explanation = domains_set.explain("Another.ru.").await;
+    assert!(explanation.is_blocked);
+    assert_eq!(explanation.matches[0].line, Some(2));
+    assert!(explanation.reason.contains("imported from source 'included'"));
+    let explanation = domains_set.explain("nx.ru").await;
+    assert!(!explanation.is_blocked);
+    assert!(explanation.reason.contains("removed by source 'excluded'"));
+    assert!(explanation.errors.is_empty());
+
+    // Source is unavailable, domains are loaded from the cache file
+    fs::remove_file(&included_path).unwrap();
+    domains_set.import_domains().await.unwrap();
+    assert!(domains_set.is_domain_blocked("blocked.ru").await);
+    assert!(!domains_set.is_domain_blocked("nx.ru").await);
+    // Prefixes are imported again with the domains
+    assert!(domains_set.is_addr_blocked(&"10.1.2.3".parse().unwrap()).await);
+}
+
+#[tokio::test]
+async fn test_domains_set_import_with_failed_source() {
+    use super::domain_source::{SourceFormat, SourceLocation};
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let workdir = tempdir.path().to_path_buf();
+    let included_path = workdir.join("included_source.txt");
+    fs::write(&included_path, "blocked.ru\n").unwrap();
+
+    let mut domains_set = DomainsSet::new(&workdir);
+    domains_set.sources = vec![
+        DomainSource::new(
+            "included",
+            SourceLocation::Path(included_path),
+            SourceFormat::Plain,
+            SourceRole::Include,
+        ),
+        // Neither the list nor its cache file exist
+        DomainSource::new(
+            "missing",
+            SourceLocation::Path(workdir.join("missing_source.txt")),
+            SourceFormat::Plain,
+            SourceRole::Include,
+        ),
+    ];
+    let report = domains_set.import_domains().await.unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(report.source_errors.len(), 1);
+    assert_eq!(report.source_errors[0].0, "missing");
+    assert!(domains_set.is_domain_blocked("blocked.ru").await);
+}
+
+#[tokio::test]
+async fn test_domains_set_import_subdomains_and_exceptions() {
+    use super::domain_source::{SourceFormat, SourceLocation};
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let workdir = tempdir.path().to_path_buf();
+    let source = DomainSource::new(
+        "rpz",
+        SourceLocation::Path(workdir.join("rpz_source.txt")),
+        SourceFormat::Plain,
+        SourceRole::Include,
+    );
+    // As cached by RPZ: "*.bad.org" and passthru "ok.bad.org"
+    fs::write(source.cache_filepath(&workdir), "+.bad.org\n@@ok.bad.org\n").unwrap();
+
+    let mut domains_set = DomainsSet::new(&workdir);
+    domains_set.sources = vec![source];
+    assert!(domains_set.import_cached_domains().await.unwrap().is_ok());
+    assert!(domains_set.is_domain_blocked("a.bad.org").await);
+    assert!(!domains_set.is_domain_blocked("bad.org").await);
+    assert!(!domains_set.is_domain_blocked("ok.bad.org").await);
+
+    let explanation = domains_set.explain("ok.bad.org").await;
+    assert!(!explanation.is_blocked);
+    assert!(explanation.reason.contains("cancelled by rule '@@ok.bad.org'"));
+}
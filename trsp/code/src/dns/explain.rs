@@ -0,0 +1,175 @@
+use std::net::IpAddr;
+use serde::Serialize;
+
+use super::domain_rules::RuleKind;
+use super::domain_source::SourceRole;
+use super::domains_set::{EXCLUDED_DOMAINS_FILENAME, INCLUDED_DOMAINS_FILENAME};
+use super::proxy_record::ProxyRecordSet;
+use super::zapret::RecordMeta;
+
+
+// Rule of the included/excluded list or of a source that matches the name
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleMatch {
+    // File of the included/excluded list or the source name
+    pub list: String,
+    pub role: SourceRole,
+    pub rule: String,
+    pub kind: RuleKind,
+    // The name itself or its parent matched by the wildcard rule
+    pub matched_name: String,
+    // Line of the list file, None if the list has no lines (geosite, rpz)
+    pub line: Option<u64>,
+    // "@@" rule, cancels other matches of its list
+    pub is_exception: bool,
+    // Registry record the rule is parsed from (zapret_csv sources)
+    pub record: Option<RecordMeta>,
+}
+
+// Mapping of the name in the inner storage
+#[derive(Debug, Clone, Serialize)]
+pub struct Mapping {
+    pub record_type: String,
+    pub original_addr: IpAddr,
+    pub mapped_addr: IpAddr,
+    // RFC 3339 time the mapping is removed at, None if it's in use
+    pub cleanup_at: Option<String>,
+}
+
+impl Mapping {
+    pub fn from_record_set(record_type: &str, records_set: &ProxyRecordSet) -> Vec<Self> {
+        records_set.records()
+            .iter()
+            .filter(|r| r.is_routable())
+            .map(|r| Self {
+                record_type: String::from(record_type),
+                original_addr: r.original_addr.unwrap(),
+                mapped_addr: r.mapped_addr.unwrap(),
+                cleanup_at: r.cleanup_at.map(|t| t.to_rfc3339()),
+            })
+            .collect()
+    }
+}
+
+// Original address of the mapping blocked by a prefix or a GeoIP rule
+#[derive(Debug, Clone, Serialize)]
+pub struct AddrMatch {
+    pub addr: IpAddr,
+    // "prefix", "AS13335", "country NL"
+    pub reason: String,
+}
+
+// Why the name is routed through the tunnel or not
+#[derive(Debug, Clone, Serialize)]
+pub struct Explanation {
+    pub name: String,
+    pub is_blocked: bool,
+    // The rule that made the decision
+    pub reason: String,
+    pub matches: Vec<RuleMatch>,
+    pub mappings: Vec<Mapping>,
+    pub addr_matches: Vec<AddrMatch>,
+    // Sources that couldn't be checked
+    pub errors: Vec<String>,
+}
+
+impl Explanation {
+    // Reason by the precedence of DomainsSet::is_domain_blocked: the excluded
+    // list, the included list, then imported domains without exclude sources
+    pub fn new(name: &str, is_blocked: bool, matches: Vec<RuleMatch>) -> Self {
+        let reason = Explanation::reason(&matches);
+        Self {
+            name: String::from(name),
+            is_blocked,
+            reason,
+            matches,
+            mappings: vec![],
+            addr_matches: vec![],
+            errors: vec![],
+        }
+    }
+
+    // Names which are not blocked by rules are mapped by blocked addresses
+    // of their answers
+    pub fn add_addr_matches(&mut self, addr_matches: Vec<AddrMatch>) {
+        if let (false, Some(m)) = (self.is_blocked, addr_matches.first()) {
+            self.reason = format!("address {} is blocked by {}; {}", m.addr, m.reason, self.reason);
+            self.is_blocked = true;
+        }
+        self.addr_matches = addr_matches;
+    }
+
+    fn reason(matches: &[RuleMatch]) -> String {
+        // Matches that didn't make the decision
+        let mut notes = vec![];
+        let with_notes = |reason: String, notes: &Vec<String>| match notes.is_empty() {
+            true => reason,
+            false => format!("{}; {}", reason, notes.join("; ")),
+        };
+        for list in [EXCLUDED_DOMAINS_FILENAME, INCLUDED_DOMAINS_FILENAME] {
+            let rule = matches.iter().find(|m| m.list == list && ! m.is_exception);
+            let exception = matches.iter().find(|m| m.list == list && m.is_exception);
+            match (rule, exception) {
+                (Some(r), None) => return with_notes(format!("{} of {}", r.describe(), list), &notes),
+                (Some(r), Some(e)) => notes.push(format!(
+                    "{} of {} is cancelled by {}", r.describe(), list, e.describe(),
+                )),
+                _ => {},
+            }
+        }
+        let source_matches = |role| matches.iter()
+            .filter(move |m| {
+                m.role == role && m.list != INCLUDED_DOMAINS_FILENAME && m.list != EXCLUDED_DOMAINS_FILENAME
+            });
+        let source_rules = |role| source_matches(role).filter(|m| ! m.is_exception);
+        // Exceptions of sources cancel all imported domains
+        let source_exception = source_matches(SourceRole::Include).find(|m| m.is_exception);
+        for m in source_rules(SourceRole::Include) {
+            if let Some(e) = source_exception {
+                notes.push(format!(
+                    "{} of source '{}' is cancelled by {} of source '{}'",
+                    m.describe(), m.list, e.describe(), e.list,
+                ));
+                continue
+            }
+            // Exclude sources remove the same names of include sources,
+            // the name and its wildcard remove each other
+            let is_removed_by = |e: &&RuleMatch| {
+                e.rule == m.rule
+                    || e.rule.strip_prefix("*.") == Some(m.rule.as_str())
+                    || m.rule.strip_prefix("*.") == Some(e.rule.as_str())
+            };
+            match source_rules(SourceRole::Exclude).find(is_removed_by) {
+                Some(e) => notes.push(format!(
+                    "{} of source '{}' is removed by source '{}'", m.describe(), m.list, e.list,
+                )),
+                None => return with_notes(
+                    format!("{} imported from source '{}'", m.describe(), m.list),
+                    &notes,
+                ),
+            }
+        }
+        with_notes(String::from("no rule matches"), &notes)
+    }
+}
+
+impl RuleMatch {
+    fn describe(&self) -> String {
+        let line = self.line.map(|l| format!(" (line {})", l)).unwrap_or_default();
+        format!("rule '{}'{} matching '{}'", self.rule, line, self.matched_name)
+    }
+}
+
+
+#[test]
+fn test_explanation_addr_matches() {
+    let mut explanation = Explanation::new("example.com", false, vec![]);
+    explanation.add_addr_matches(vec![]);
+    assert!(!explanation.is_blocked);
+    assert_eq!(explanation.reason, "no rule matches");
+
+    let addr: IpAddr = "10.1.2.3".parse().unwrap();
+    explanation.add_addr_matches(vec![AddrMatch { addr, reason: String::from("prefix") }]);
+    assert!(explanation.is_blocked);
+    assert_eq!(explanation.reason, "address 10.1.2.3 is blocked by prefix; no rule matches");
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use serde::Deserialize;
+use tracing::debug;
+
+
+// [dns.geoip] section of the config file. Answers of names that are not
+// blocked by domains are checked against the rules
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct GeoIpConfig {
+    // MaxMind format databases: GeoLite2-ASN.mmdb, GeoLite2-Country.mmdb
+    pub asn_database: Option<PathBuf>,
+    pub country_database: Option<PathBuf>,
+    // Autonomous system numbers: [13335]
+    #[serde(default)]
+    pub asns: Vec<u32>,
+    // ISO codes of countries: ["NL"]
+    #[serde(default)]
+    pub countries: Vec<String>,
+}
+
+pub struct GeoIp {
+    asn_reader: Option<Reader<Vec<u8>>>,
+    country_reader: Option<Reader<Vec<u8>>>,
+    asns: HashSet<u32>,
+    countries: HashSet<String>,
+}
+
+impl GeoIp {
+    // None if there are no rules
+    pub fn from_config(config: &GeoIpConfig) -> Result<Option<Self>, Box<dyn Error>> {
+        if config.asns.is_empty() && config.countries.is_empty() {
+            return Ok(None)
+        }
+        let asn_reader = match (&config.asn_database, config.asns.is_empty()) {
+            (Some(path), false) => Some(open_database(path)?),
+            (None, false) => return Err("GeoIP asns are set without asn_database".into()),
+            _ => None,
+        };
+        let country_reader = match (&config.country_database, config.countries.is_empty()) {
+            (Some(path), false) => Some(open_database(path)?),
+            (None, false) => return Err("GeoIP countries are set without country_database".into()),
+            _ => None,
+        };
+        Ok(Some(Self {
+            asn_reader,
+            country_reader,
+            asns: config.asns.iter().copied().collect(),
+            countries: config.countries.iter().map(|c| c.to_uppercase()).collect(),
+        }))
+    }
+
+    // The rule the address matches: "AS13335", "country NL"
+    pub fn find(&self, addr: IpAddr) -> Option<String> {
+        if let Some(reader) = &self.asn_reader {
+            if let Some(asn) = lookup::<geoip2::Asn>(reader, addr).and_then(|r| r.autonomous_system_number) {
+                if self.asns.contains(&asn) {
+                    return Some(format!("AS{}", asn))
+                }
+            }
+        }
+        if let Some(reader) = &self.country_reader {
+            let country = lookup::<geoip2::Country>(reader, addr)
+                .and_then(|r| r.country)
+                .and_then(|c| c.iso_code);
+            if let Some(code) = country {
+                if self.countries.contains(code) {
+                    return Some(format!("country {}", code))
+                }
+            }
+        }
+        None
+    }
+}
+
+fn open_database(path: &Path) -> Result<Reader<Vec<u8>>, Box<dyn Error>> {
+    Reader::open_readfile(path)
+        .map_err(|e| format!("GeoIP database '{}': {}", path.display(), e).into())
+}
+
+// Addresses that are not in the database are not matched
+fn lookup<'a, T: Deserialize<'a>>(reader: &'a Reader<Vec<u8>>, addr: IpAddr) -> Option<T> {
+    match reader.lookup::<T>(addr) {
+        Ok(r) => Some(r),
+        Err(MaxMindDBError::AddressNotFoundError(_)) => None,
+        Err(e) => {
+            debug!("GeoIP lookup of {}: {}", addr, e);
+            None
+        },
+    }
+}
+
+
+#[test]
+fn test_geoip_from_config() {
+    assert!(GeoIp::from_config(&GeoIpConfig::default()).unwrap().is_none());
+    let config = GeoIpConfig { asns: vec![13335], ..Default::default() };
+    assert!(GeoIp::from_config(&config).is_err());
+    let config = GeoIpConfig {
+        country_database: Some(PathBuf::from("/nonexistent/GeoLite2-Country.mmdb")),
+        countries: vec![String::from("nl")],
+        ..Default::default()
+    };
+    let err = GeoIp::from_config(&config).err().unwrap().to_string();
+    assert!(err.contains("GeoLite2-Country.mmdb"), "{}", err);
+}
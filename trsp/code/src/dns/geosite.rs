@@ -0,0 +1,141 @@
+use std::error::Error;
+use prost::Message;
+use tracing::{debug, warn};
+
+use super::domains::Domains;
+use super::domain_source::prepare_domain_name;
+
+
+const GEOSITE_DOMAINS_HASHSET_CAP: usize = 1024;
+
+
+// v2fly/Xray geosite.dat messages, see
+// https://github.com/v2fly/v2ray-core/blob/master/app/router/routercommon/common.proto
+#[derive(Clone, PartialEq, Message)]
+struct GeoSiteList {
+    #[prost(message, repeated, tag = "1")]
+    entry: Vec<GeoSite>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GeoSite {
+    #[prost(string, tag = "1")]
+    country_code: String,
+    #[prost(message, repeated, tag = "2")]
+    domain: Vec<GeoDomain>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GeoDomain {
+    #[prost(enumeration = "GeoDomainType", tag = "1")]
+    r#type: i32,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+enum GeoDomainType {
+    // Keyword, substring of the name
+    Plain = 0,
+    Regex = 1,
+    // The domain and all its subdomains
+    Domain = 2,
+    Full = 3,
+}
+
+
+// Parse geosite.dat and take the rules of selected categories (case insensitive).
+// Returns domains and the count of invalid rules
+pub fn parse_geosite(data: &[u8], categories: &[String]) -> Result<(Domains, u64), Box<dyn Error>> {
+    let list = GeoSiteList::decode(data)?;
+    let mut domains = Domains::new(Some(GEOSITE_DOMAINS_HASHSET_CAP));
+    let mut errors_count: u64 = 0;
+
+    for category in categories {
+        let site = match list.entry.iter().find(|s| s.country_code.eq_ignore_ascii_case(category)) {
+            Some(s) => s,
+            None => {
+                warn!("Geosite category '{}' not found", category);
+                errors_count += 1;
+                continue
+            }
+        };
+        for rule in &site.domain {
+            if let Err(e) = add_geosite_rule(&mut domains, rule) {
+                debug!("Error in geosite category '{}' rule '{}': {}", category, rule.value, e);
+                errors_count += 1;
+            }
+        }
+    }
+    domains.build_regex_set()?;
+    Ok((domains, errors_count))
+}
+
+fn add_geosite_rule(domains: &mut Domains, rule: &GeoDomain) -> Result<(), String> {
+    let value = rule.value.to_lowercase();
+    let rule_type = GeoDomainType::try_from(rule.r#type)
+        .map_err(|_| format!("unknown rule type {}", rule.r#type))?;
+    match rule_type {
+        GeoDomainType::Plain => {
+            if value.is_empty() {
+                return Err(String::from("empty keyword"))
+            }
+            domains.add_keyword(&value);
+        },
+        GeoDomainType::Regex => {
+            domains.add_regex(&rule.value).map_err(|e| e.to_string())?;
+        },
+        GeoDomainType::Domain | GeoDomainType::Full => {
+            let domain = prepare_domain_name(&value);
+            if domain.is_empty() {
+                return Err(String::from("invalid domain"))
+            }
+            // "*.domain" matches the domain itself and all subdomains
+            if rule_type == GeoDomainType::Domain {
+                domains.set(&format!("*.{}", domain));
+            } else {
+                domains.set(&domain);
+            }
+        },
+    }
+    Ok(())
+}
+
+
+#[test]
+fn test_geosite_parse() {
+    let rule = |rule_type: GeoDomainType, value: &str| GeoDomain {
+        r#type: rule_type as i32,
+        value: String::from(value),
+    };
+    let list = GeoSiteList {
+        entry: vec![
+            GeoSite {
+                country_code: String::from("OPENAI"),
+                domain: vec![
+                    rule(GeoDomainType::Domain, "openai.com"),
+                    rule(GeoDomainType::Full, "cdn.oaistatic.com"),
+                    rule(GeoDomainType::Plain, "chatgpt"),
+                    rule(GeoDomainType::Regex, r"^rr[0-9]+\.oai\.com$"),
+                    rule(GeoDomainType::Domain, "bad domain"),
+                ],
+            },
+            GeoSite {
+                country_code: String::from("CN"),
+                domain: vec![rule(GeoDomainType::Domain, "baidu.com")],
+            },
+        ],
+    };
+    let data = list.encode_to_vec();
+    let (domains, errors_count) = parse_geosite(
+        &data, &[String::from("openai"), String::from("missing")],
+    ).unwrap();
+    assert_eq!(errors_count, 2);
+    assert!(domains.get("*.openai.com").is_some());
+    assert!(domains.get("cdn.oaistatic.com").is_some());
+    assert!(domains.get("*.baidu.com").is_none());
+    assert!(domains.is_match_patterns("chatgpt.example"));
+    assert!(domains.is_match_patterns("rr12.oai.com"));
+    assert!(!domains.is_match_patterns("rr.oai.com"));
+}
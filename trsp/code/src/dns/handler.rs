@@ -1,19 +1,27 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{cmp, sync::Arc, time::Duration};
 
 use crate::options::Options;
 use hickory_server::{
-    proto::op::{Header, OpCode, MessageType, ResponseCode},
+    proto::op::{Edns, Header, OpCode, MessageType, ResponseCode},
     server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
     store::forwarder::ForwardConfig,
     resolver::config::{NameServerConfigGroup, ResolverOpts},
-    authority::Catalog,
+    authority::{Catalog, LookupOptions, MessageResponseBuilder},
 };
 
-use hickory_proto::rr::{LowerName, Name};
-use tracing::error;
+use hickory_proto::rr::{LowerName, Name, Record};
+use tracing::{debug, error, warn};
 
+use super::config::ZoneConfig;
 use super::domains_set::ArcDomainsSet;
-use super::trsp_authority::TrspAuthority;
+use super::inner_storage::InnerStorage;
+use super::response_policy::ResponsePolicy;
+use super::reverse_authority::ReverseAuthority;
+use super::router::Router;
+use super::trsp_authority::{new_mapping_pool, SharedContext, TrspAuthority, ZoneAuthorities};
+use super::upstream_manager::{SelectionConfig, UpstreamManager, UpstreamsMonitor};
+use super::upstreams::{add_name_servers, UpstreamGroupConfig, UpstreamsConfig};
+use tokio::sync::RwLock;
 use std::error::Error;
 
 
@@ -32,82 +40,160 @@ pub enum DnsError {
 
 
 pub struct Handler {
-    pub domains: ArcDomainsSet,
     // forwarder_authority: Catalog,
     trsp_authority: Catalog,
+    response_policy: ResponsePolicy,
 }
 
 
 #[allow(dead_code)]
 impl Handler {
-    pub fn new(options: &Options, domains: ArcDomainsSet) -> Result<Self, Box<dyn Error>> {
+    pub fn new(
+        options: &Options,
+        domains: ArcDomainsSet,
+        router: Arc<dyn Router>,
+        inner_storage: Arc<RwLock<InnerStorage>>,
+        zones: &[ZoneConfig],
+        upstreams: &UpstreamsConfig,
+        upstreams_monitor: &UpstreamsMonitor,
+    ) -> Result<Self, Box<dyn Error>> {
         // https://github.com/bluejekyll/trust-dns/blob/main/crates/resolver/src/config.rs
-        let trsp_authority = Self::create_trsp_authority(options, domains.clone())?;
+        let trsp_authority = Self::create_trsp_authority(
+            options, domains, router, inner_storage, zones, upstreams, upstreams_monitor,
+        )?;
         Ok(Handler {
-            domains,
             trsp_authority,
+            response_policy: ResponsePolicy::new(Duration::from_secs(options.dns_negative_max_ttl)),
         })
     }
 
-    fn add_clear_resolvers(
-        resolvers: &Vec<SocketAddr>,
-        ns_group: &mut NameServerConfigGroup
-    )
-    // Add resolvers from Options to NS Config group
-    {
-        for socket in resolvers {
-            let ip = &[socket.ip()];
-            let port = socket.port();
-            ns_group.merge(NameServerConfigGroup::from_ips_clear(
-                ip, port, true
-            ));
-        }
-    }
+    fn create_trsp_authority(
+        options: &Options,
+        blocked_domains_set: ArcDomainsSet,
+        router: Arc<dyn Router>,
+        inner_storage: Arc<RwLock<InnerStorage>>,
+        zones: &[ZoneConfig],
+        upstreams: &UpstreamsConfig,
+        upstreams_monitor: &UpstreamsMonitor,
+    ) -> Result<Catalog, Box<dyn Error>> {
+        let blocked_forwarder = Handler::create_upstream_manager(
+            "blocked", upstreams.blocked.as_ref(), options, upstreams_monitor,
+        )?;
+        let default_forwarder = Handler::create_upstream_manager(
+            "default", upstreams.default.as_ref(), options, upstreams_monitor,
+        )?;
+        let zone_authorities = ZoneAuthorities::default();
+        let shared = SharedContext {
+            router,
+            inner_storage: inner_storage.clone(),
+            mapping_pool: new_mapping_pool(options.dns_mapping_ipv4_subnet),
+            zones: zone_authorities.clone(),
+        };
 
-    fn add_tls_resolvers(
-        resolvers: &Vec<SocketAddr>,
-        ns_group: &mut NameServerConfigGroup
-    )
-    // Add resolvers from Options to NS Config group
-    {
-        for socket in resolvers {
-            let ip = &[socket.ip()];
-            let port = socket.port();
-            ns_group.merge(NameServerConfigGroup::from_ips_tls(
-                ip, port, socket.ip().to_string(), true
-            ));
+        let root = LowerName::new(&Name::root());
+        let trsp_authority = Arc::new(TrspAuthority::new(
+            root.clone(),
+            blocked_domains_set.clone(),
+            blocked_forwarder,
+            default_forwarder,
+            shared.clone(),
+            options,
+        )?);
+        zone_authorities.write().unwrap().push((root.clone(), Arc::downgrade(&trsp_authority)));
+        let mut catalog = Catalog::new();
+        catalog.upsert(root, Box::new(trsp_authority));
+        // Conditional forwarding: names of the zone go to its upstreams,
+        // blocked ones are mapped as usual
+        let mut err: Vec<String> = vec![];
+        for zone_config in zones {
+            let zone = match Name::from_str_relaxed(&zone_config.zone) {
+                Ok(mut n) => {
+                    n.set_fqdn(true);
+                    LowerName::from(n)
+                },
+                Err(e) => {
+                    err.push(format!("Zone '{}': {}", zone_config.zone, e));
+                    continue
+                },
+            };
+            if zone_config.upstreams.is_empty() {
+                err.push(format!("Zone '{}' has no upstreams", zone));
+                continue
+            }
+            let mut name_servers = NameServerConfigGroup::new();
+            add_name_servers(&zone_config.upstreams, None, &mut name_servers);
+            let zone_forwarder_config = ForwardConfig {
+                name_servers,
+                options: Some(Handler::create_resolver_options(options)),
+            };
+            let zone_forwarder = UpstreamManager::new(
+                &format!("zone {}", zone),
+                &zone_forwarder_config,
+                Handler::create_selection(options),
+                upstreams_monitor,
+            )?;
+            // Names of the zone aren't expected to be blocked, both groups
+            // are the zone upstreams
+            let zone_authority = Arc::new(TrspAuthority::new(
+                zone.clone(),
+                blocked_domains_set.clone(),
+                zone_forwarder.clone(),
+                zone_forwarder,
+                shared.clone(),
+                options,
+            )?);
+            debug!("Zone {} is forwarded to {:?}", zone, zone_config.upstreams);
+            zone_authorities.write().unwrap().push((zone.clone(), Arc::downgrade(&zone_authority)));
+            catalog.upsert(zone, Box::new(zone_authority));
         }
-    }
-
-    fn add_https_resolvers(
-        resolvers: &Vec<SocketAddr>,
-        ns_group: &mut NameServerConfigGroup
-    )
-    // Add resolvers from Options to NS Config group
-    {
-        for socket in resolvers {
-            let ip = &[socket.ip()];
-            let port = socket.port();
-            ns_group.merge(NameServerConfigGroup::from_ips_https(
-                ip, port, socket.ip().to_string(), true
-            ));
+        if ! err.is_empty() {
+            return Err(err.join(". ").into())
         }
+        // PTR of mapped addresses
+        let mapping_subnet = options.dns_mapping_ipv4_subnet.into();
+        for zone in ReverseAuthority::zones(&mapping_subnet) {
+            let reverse_authority = ReverseAuthority::new(
+                zone.clone(),
+                mapping_subnet,
+                inner_storage.clone(),
+                options.dns_positive_max_ttl.try_into().unwrap_or(u32::MAX),
+            );
+            catalog.upsert(zone, Box::new(Arc::new(reverse_authority)));
+        }
+        Ok(catalog)
     }
 
-    fn create_trsp_authority(options: &Options, blocked_domains_set: ArcDomainsSet) -> Result<Catalog, Box<dyn Error>> {
-        let forwarder_config = &Handler::create_forwarder_config(options);
+    // The group of the config file, the command line resolvers without it
+    fn create_upstream_manager(
+        group_name: &str,
+        group: Option<&UpstreamGroupConfig>,
+        options: &Options,
+        monitor: &UpstreamsMonitor,
+    ) -> Result<Arc<UpstreamManager>, Box<dyn Error>> {
+        let selection = Handler::create_selection(options);
+        let group = match group {
+            Some(g) => g,
+            None => {
+                let forward_config = Handler::create_forwarder_config(options);
+                return UpstreamManager::new(group_name, &forward_config, selection, monitor)
+            },
+        };
+        let name_servers = group.name_servers()
+            .map_err(|e| format!("Upstreams '{}': {}", group_name, e))?;
+        debug!("Upstreams '{}': {:?} {:?}", group_name, group.protocol, group.servers);
+        let forward_config = ForwardConfig {
+            name_servers,
+            options: Some(group.resolver_options(Handler::create_resolver_options(options))),
+        };
+        UpstreamManager::new(group_name, &forward_config, group.selection(selection), monitor)
+    }
 
-        let trsp_authority = TrspAuthority::new(
-            blocked_domains_set,
-            forwarder_config,
-            &options,
-        )?;
-        let mut catalog = Catalog::new();
-        catalog.upsert(
-            LowerName::new(&Name::root()),
-            Box::new(Arc::new(trsp_authority)),
-        );
-        return Ok(catalog)
+    fn create_selection(options: &Options) -> SelectionConfig {
+        SelectionConfig {
+            strategy: options.dns_upstream_strategy,
+            race: options.dns_upstream_race,
+            health_check_interval: Duration::from_secs(options.dns_upstream_health_check_interval_secs),
+        }
     }
 
     fn create_forwarder_config(options: &Options) -> ForwardConfig {
@@ -115,9 +201,9 @@ impl Handler {
         let name_servers_ref = &mut name_servers;
 
         if let Some(https_resolvers) = &options.dns_https_resolvers {
-            Handler::add_https_resolvers(&https_resolvers, name_servers_ref)
+            add_name_servers(https_resolvers, None, name_servers_ref)
         } else if let Some(plain_resolvers) = &options.dns_resolvers {
-            Handler::add_clear_resolvers(&plain_resolvers, name_servers_ref)
+            add_name_servers(plain_resolvers, None, name_servers_ref)
         } else if options.dns_https_resolvers_enabled {
             name_servers.merge(NameServerConfigGroup::cloudflare_https());
         } else {
@@ -125,6 +211,13 @@ impl Handler {
             name_servers.merge(NameServerConfigGroup::google());
         }
 
+        return ForwardConfig{
+                name_servers,
+                options: Some(Handler::create_resolver_options(options)),
+            }
+    }
+
+    fn create_resolver_options(options: &Options) -> ResolverOpts {
         let mut resolver_options = ResolverOpts::default();
         resolver_options.edns0 = false;
         resolver_options.validate = false;
@@ -134,27 +227,99 @@ impl Handler {
         resolver_options.negative_max_ttl = Some(Duration::from_secs(options.dns_negative_max_ttl));
         resolver_options.positive_min_ttl = Some(Duration::from_secs(options.dns_positive_min_ttl));
         resolver_options.negative_min_ttl = Some(Duration::from_secs(options.dns_negative_min_ttl));
-        return ForwardConfig{
-                name_servers,
-                options: Some(resolver_options),
-            }
+        resolver_options
     }
 
 
+    // Queries are answered here instead of Catalog::handle_request: errors of
+    // forwarded lookups get RCODEs and SOAs of the response policy there
     async fn do_handle_request<R: ResponseHandler> (
         &self,
         request: &Request,
         response: R,
     ) -> Result<ResponseInfo, DnsError> {
-        // TODO: return error to client immediately
+        if request.message_type() != MessageType::Query {
+            warn!("Response as a request from id {}", request.id());
+            return self.send_error(request, response, ResponseCode::Refused).await
+        }
         if request.op_code() != OpCode::Query {
-            return Err(DnsError::InvalidOpCode(request.op_code()))
+            warn!("Unimplemented OpCode {} from id {}", request.op_code(), request.id());
+            return self.send_error(request, response, ResponseCode::NotImp).await
         }
-        if request.message_type() != MessageType::Query {
-            return Err(DnsError::InvalidMessageType(request.message_type()));
+        let edns = match request.edns() {
+            Some(req_edns) if req_edns.version() > 0 => {
+                warn!("Unsupported EDNS version {} from id {}", req_edns.version(), request.id());
+                return self.send_error(request, response, ResponseCode::BADVERS).await
+            },
+            Some(req_edns) => {
+                let mut edns = Edns::new();
+                edns.set_max_payload(cmp::max(512, req_edns.max_payload()));
+                edns.set_dnssec_ok(false);
+                Some(edns)
+            },
+            None => None,
+        };
+        self.lookup(request, edns, response).await
+    }
+
+    async fn lookup<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        edns: Option<Edns>,
+        mut response: R,
+    ) -> Result<ResponseInfo, DnsError> {
+        let request_info = request.request_info();
+        let query = request_info.query;
+        let authority = match self.trsp_authority.find(query.name()) {
+            Some(a) => a,
+            None => return self.send_error(request, response, ResponseCode::Refused).await,
+        };
+        let name = Name::from(query.name());
+        let origin = Name::from(authority.origin());
+        let query_type = query.query_type();
+
+        let mut header = Header::response_from_request(request.header());
+        header.set_recursion_available(true);
+        header.set_authoritative(authority.zone_type().is_authoritative());
+        let mut answers: Vec<Record> = vec![];
+        let mut soa: Vec<Record> = vec![];
+        match authority.search(request_info, LookupOptions::default()).await {
+            Ok(lookup) => {
+                answers.extend(lookup.iter().cloned());
+                if answers.is_empty() {
+                    soa.extend(self.response_policy.negative_soa(&origin, None));
+                }
+            },
+            Err(e) => {
+                let code = self.response_policy.response_code(&e);
+                if code == ResponseCode::ServFail {
+                    error!("Lookup {} {}: {}", name, query_type, e);
+                } else {
+                    debug!("Lookup {} {}: {} ({})", name, query_type, code, e);
+                }
+                header.set_response_code(code);
+                soa.extend(self.response_policy.negative_soa(&origin, Some(&e)));
+            },
         }
-        // TODO: Зачем тут OK
-        Ok(self.trsp_authority.handle_request(request, response).await)
+
+        let mut message = MessageResponseBuilder::from_message_request(request)
+            .build(header, answers.iter(), [].iter(), soa.iter(), [].iter());
+        if let Some(edns) = edns {
+            message.set_edns(edns);
+        }
+        Ok(response.send_response(message).await?)
+    }
+
+    // The error keeps ID and question of the request
+    async fn send_error<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        mut response: R,
+        code: ResponseCode,
+    ) -> Result<ResponseInfo, DnsError> {
+        let message = MessageResponseBuilder::from_message_request(request)
+            .error_msg(request.header(), code);
+        Ok(response.send_response(message).await?)
     }
 }
 
@@ -170,11 +335,10 @@ impl RequestHandler for Handler {
             Ok(info) => info,
             Err(err) => {
                 error!("Error in RequestHandler: {err}");
-                let mut header = Header::new();
+                let mut header = Header::response_from_request(request.header());
                 header.set_response_code(ResponseCode::ServFail);
                 header.into()
             }
         }
     }
 }
-
@@ -1,19 +1,27 @@
 use std::{
     error::Error,
     collections::HashMap,
+    net::IpAddr,
+    path::PathBuf,
     sync::Arc,
 };
+use tokio::{
+    io::AsyncWriteExt,
+    fs::File,
+};
 
 
 use hickory_proto::rr::{LowerName, RecordType, RrKey};
 
+use super::explain::Mapping;
 use super::proxy_record::ProxyRecordSet;
 
 
 #[derive(Default)]
 pub struct InnerStorage {
     records: HashMap<RrKey, Arc<ProxyRecordSet>>,
-    // internal_ip_to_record: HashMap<IpAddr, RrKey>,
+    // Mapped addresses of the records, for PTR answers
+    internal_ip_to_record: HashMap<IpAddr, RrKey>,
 }
 
 impl InnerStorage {
@@ -21,7 +29,7 @@ impl InnerStorage {
     pub fn new() -> Self {
         Self {
             records: HashMap::new(),
-            // internal_ip_to_record: HashMap::new(),
+            internal_ip_to_record: HashMap::new(),
         }
     }
 
@@ -36,13 +44,88 @@ impl InnerStorage {
         records_set: &ProxyRecordSet
     ) -> Result<Arc<ProxyRecordSet>, Box<dyn Error>> {
         let records_set = Arc::from(records_set.clone());
-        self.records.insert(
-            RrKey::new(name.clone(), rtype.clone()),
-            records_set.clone(),
-        );
+        let key = RrKey::new(name.clone(), rtype.clone());
+        if let Some(previous) = self.records.insert(key.clone(), records_set.clone()) {
+            for addr in previous.records().iter().filter_map(|r| r.mapped_addr) {
+                if self.internal_ip_to_record.get(&addr) == Some(&key) {
+                    self.internal_ip_to_record.remove(&addr);
+                }
+            }
+        }
+        for addr in records_set.records().iter().filter_map(|r| r.mapped_addr) {
+            self.internal_ip_to_record.insert(addr, key.clone());
+        }
         Ok(records_set)
     }
 
+    // The records set the mapped address is assigned to
+    pub fn find_by_mapped_addr(&self, addr: &IpAddr) -> Option<Arc<ProxyRecordSet>> {
+        let key = self.internal_ip_to_record.get(addr)?;
+        self.records.get(key).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    // Write all mappings as tab separated lines:
+    // domain, record type, original addr, mapped addr, cleanup time
+    pub async fn write_snapshot(&self, write_to: &PathBuf) -> Result<(), Box<dyn Error>> {
+        let mut file = File::create(write_to).await?;
+        for (key, records_set) in &self.records {
+            for record in records_set.records() {
+                if ! record.is_routable() {
+                    continue
+                }
+                let line = format!(
+                    "{}\t{}\t{}\t{}\t{}\n",
+                    records_set.domain,
+                    key.record_type,
+                    record.original_addr.unwrap(),
+                    record.mapped_addr.unwrap(),
+                    record.cleanup_at.map(|t| t.to_rfc3339()).unwrap_or_else(|| "-".into()),
+                );
+                file.write_all(line.as_bytes()).await?;
+            }
+        }
+        file.flush().await?;
+        Ok(())
+    }
+
+    pub fn mappings(&self, name: &LowerName) -> Vec<Mapping> {
+        let mut mappings = vec![];
+        for rtype in [RecordType::A, RecordType::AAAA] {
+            if let Some(records_set) = self.inner_lookup(name, rtype) {
+                mappings.extend(Mapping::from_record_set(&rtype.to_string(), &records_set));
+            }
+        }
+        mappings
+    }
+
+    // Mappings of the name from the snapshot written by write_snapshot
+    pub async fn read_snapshot_mappings(read_from: &PathBuf, name: &str)
+        -> Result<Vec<Mapping>, Box<dyn Error>>
+    {
+        let mut mappings = vec![];
+        let data = tokio::fs::read_to_string(read_from).await?;
+        for line in data.lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 5 || fields[0].trim_end_matches('.') != name {
+                continue
+            }
+            mappings.push(Mapping {
+                record_type: String::from(fields[1]),
+                original_addr: fields[2].parse::<IpAddr>()?,
+                mapped_addr: fields[3].parse::<IpAddr>()?,
+                cleanup_at: match fields[4] {
+                    "-" => None,
+                    t => Some(String::from(t)),
+                },
+            });
+        }
+        Ok(mappings)
+    }
+
     fn inner_lookup(
         &self,
         name: &LowerName,
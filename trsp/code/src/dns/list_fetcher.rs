@@ -0,0 +1,260 @@
+use std::{
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use async_compression::tokio::bufread::{GzipDecoder, XzDecoder};
+use reqwest::{
+    Client,
+    Proxy,
+    Url,
+    StatusCode,
+    header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, BufReader};
+use tokio_stream::StreamExt;
+use tokio_util::io::StreamReader;
+use tracing::debug;
+
+use crate::options::Options;
+
+
+const VALIDATORS_FILENAME_SUFFIX: &str = ".http";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+pub type ListReader = Box<dyn AsyncRead + Unpin + Send>;
+
+
+// ETag and Last-Modified of the cached list version,
+// stored next to the cache file as "<header>: <value>" lines
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CacheValidators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl CacheValidators {
+    fn filepath(cache_filepath: &Path) -> PathBuf {
+        let mut path = cache_filepath.as_os_str().to_os_string();
+        path.push(VALIDATORS_FILENAME_SUFFIX);
+        PathBuf::from(path)
+    }
+
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let value = |name| headers.get(name)
+            .and_then(|v: &reqwest::header::HeaderValue| v.to_str().ok())
+            .map(String::from);
+        Self {
+            etag: value(ETAG),
+            last_modified: value(LAST_MODIFIED),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    // Validators without the cache file are useless, so there are none
+    // if the cache file doesn't exist
+    pub async fn read(cache_filepath: &Path) -> Self {
+        let mut validators = Self::default();
+        if ! cache_filepath.exists() {
+            return validators
+        }
+        let data = match tokio::fs::read_to_string(Self::filepath(cache_filepath)).await {
+            Ok(d) => d,
+            Err(_) => return validators,
+        };
+        for line in data.lines() {
+            match line.split_once(": ") {
+                Some(("etag", v)) => validators.etag = Some(String::from(v)),
+                Some(("last-modified", v)) => validators.last_modified = Some(String::from(v)),
+                _ => {},
+            }
+        }
+        validators
+    }
+
+    pub async fn write(&self, cache_filepath: &Path) -> Result<(), Box<dyn Error>> {
+        let filepath = Self::filepath(cache_filepath);
+        if self.is_empty() {
+            if filepath.exists() {
+                tokio::fs::remove_file(filepath).await?;
+            }
+            return Ok(())
+        }
+        let mut data = String::new();
+        if let Some(etag) = &self.etag {
+            data.push_str(&format!("etag: {}\n", etag));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            data.push_str(&format!("last-modified: {}\n", last_modified));
+        }
+        tokio::fs::write(filepath, data).await?;
+        Ok(())
+    }
+}
+
+
+pub enum Fetched {
+    // 304, the cached version is up to date
+    NotModified,
+    Modified(ListReader, CacheValidators),
+}
+
+// HTTP client shared by all sources, optionally through a proxy
+#[derive(Debug, Clone)]
+pub struct ListFetcher {
+    client: Client,
+    // Max time to wait for the response headers and for each body chunk
+    read_timeout: Duration,
+    // Additional attempts for each url
+    pub retries: u32,
+}
+
+impl Default for ListFetcher {
+    fn default() -> Self {
+        Self {
+            client: Client::builder()
+                .connect_timeout(DEFAULT_CONNECT_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            retries: 0,
+        }
+    }
+}
+
+impl ListFetcher {
+    pub fn new(options: &Options) -> Result<Self, Box<dyn Error>> {
+        let mut builder = Client::builder()
+            .connect_timeout(Duration::from_secs(options.dns_lists_connect_timeout_secs));
+        // http://, https://, socks5:// or socks5h:// (DNS is resolved by the proxy)
+        if let Some(proxy) = &options.dns_lists_proxy {
+            builder = builder.proxy(Proxy::all(proxy.as_str())?);
+        }
+        Ok(Self {
+            client: builder.build()?,
+            read_timeout: Duration::from_secs(options.dns_lists_read_timeout_secs),
+            retries: options.dns_lists_retries,
+        })
+    }
+
+    // GET the list, conditional if there are validators of the cached version.
+    // Compressed (gzip/xz) bodies are decompressed
+    pub async fn fetch(&self, url: &Url, validators: &CacheValidators) -> Result<Fetched, Box<dyn Error>> {
+        let mut request = self.client.get(url.clone());
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        let response = match tokio::time::timeout(self.read_timeout, request.send()).await {
+            Ok(r) => r?,
+            Err(_) => return Err(format!("no response from {} in {:?}", url, self.read_timeout).into()),
+        };
+        if response.status() == StatusCode::NOT_MODIFIED {
+            debug!("Reqwest get not modified: {}", url);
+            return Ok(Fetched::NotModified)
+        }
+        response.error_for_status_ref()?;
+        debug!("Reqwest get OK: {} {}", url, response.status());
+
+        let validators = CacheValidators::from_headers(response.headers());
+        let stream = response.bytes_stream()
+            .timeout(self.read_timeout)
+            .map(|chunk| match chunk {
+                Ok(Ok(bytes)) => Ok(bytes),
+                Ok(Err(e)) => Err(io::Error::new(io::ErrorKind::Other, e)),
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "list read timeout")),
+            });
+        let reader = decompressed(BufReader::new(StreamReader::new(Box::pin(stream)))).await?;
+        Ok(Fetched::Modified(reader, validators))
+    }
+}
+
+pub async fn open_file(path: &Path) -> Result<ListReader, Box<dyn Error>> {
+    let file = tokio::fs::File::open(path).await?;
+    decompressed(BufReader::new(file)).await
+}
+
+// Compression is detected by the magic bytes, mirrors don't always
+// set Content-Encoding or the file extension
+async fn decompressed<R>(mut reader: R) -> Result<ListReader, Box<dyn Error>>
+    where R: AsyncBufRead + Unpin + Send + 'static
+{
+    let head = reader.fill_buf().await?;
+    if head.starts_with(GZIP_MAGIC) {
+        let mut decoder = GzipDecoder::new(reader);
+        // Concatenated gzip members (e.g. from pigz) are one stream
+        decoder.multiple_members(true);
+        Ok(Box::new(decoder))
+    } else if head.starts_with(XZ_MAGIC) {
+        Ok(Box::new(XzDecoder::new(reader)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+
+#[tokio::test]
+async fn test_list_fetcher_conditional_gzip() {
+    use async_compression::tokio::bufread::GzipEncoder;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    let mut body = vec![];
+    GzipEncoder::new(&b"a.com\nb.com\n"[..]).read_to_end(&mut body).await.unwrap();
+
+    // Stand-in list server: 304 if the request has the ETag, the gzipped list otherwise
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = Url::parse(&format!("http://{}/list.gz", listener.local_addr().unwrap())).unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let n = stream.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..n]).to_lowercase();
+            let mut response = if request.contains("if-none-match: \"v1\"") {
+                b"HTTP/1.1 304 Not Modified\r\nconnection: close\r\n\r\n".to_vec()
+            } else {
+                format!(
+                    "HTTP/1.1 200 OK\r\netag: \"v1\"\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    body.len(),
+                ).into_bytes()
+            };
+            if response.starts_with(b"HTTP/1.1 200") {
+                response.extend_from_slice(&body);
+            }
+            stream.write_all(&response).await.unwrap();
+        }
+    });
+
+    let fetcher = ListFetcher::default();
+    let validators = match fetcher.fetch(&url, &CacheValidators::default()).await.unwrap() {
+        Fetched::Modified(mut reader, validators) => {
+            let mut data = String::new();
+            reader.read_to_string(&mut data).await.unwrap();
+            assert_eq!(data, "a.com\nb.com\n");
+            validators
+        },
+        Fetched::NotModified => panic!("Unconditional request is not modified"),
+    };
+    assert_eq!(validators.etag.as_deref(), Some("\"v1\""));
+    assert!(matches!(fetcher.fetch(&url, &validators).await.unwrap(), Fetched::NotModified));
+
+    let workdir = tempfile::tempdir().unwrap();
+    let cache_filepath = workdir.path().join("_trsp_source_test");
+    assert_eq!(CacheValidators::read(&cache_filepath).await, CacheValidators::default());
+    std::fs::write(&cache_filepath, "a.com\n").unwrap();
+    validators.write(&cache_filepath).await.unwrap();
+    assert_eq!(CacheValidators::read(&cache_filepath).await, validators);
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::Path,
+    str::FromStr,
+};
+use hickory_proto::rr::{
+    rdata::{A, AAAA, CNAME, TXT},
+    LowerName, Name, RData, Record, RecordType,
+};
+
+
+const DEFAULT_TTL: u32 = 300;
+const WILDCARD_PREFIX: &str = "*.";
+
+
+// Records answered instead of forwarding, from the file of lines
+// "<name> [ttl] [IN] <A|AAAA|CNAME|TXT> <value>":
+//   vps.home             A      192.0.2.10
+//   *.dev.home      60   A      192.0.2.11
+//   youtube.com          CNAME  restrict.youtube.com
+//   vps.home             TXT    "v=spf1 -all"
+// "*.name" matches subdomains of the name, exact names go first, then
+// the closest wildcard. A name with a CNAME can't have other records
+#[derive(Debug, Default)]
+pub struct LocalRecords {
+    exact: HashMap<LowerName, Vec<(u32, RData)>>,
+    // By the name after "*."
+    wildcards: HashMap<LowerName, Vec<(u32, RData)>>,
+}
+
+impl LocalRecords {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(filepath: &Path) -> Result<Self, Box<dyn Error>> {
+        let data = std::fs::read_to_string(filepath)
+            .map_err(|e| format!("Local records '{}': {}", filepath.display(), e))?;
+        Self::parse(&data).map_err(|e| format!("Local records '{}': {}", filepath.display(), e).into())
+    }
+
+    pub fn parse(data: &str) -> Result<Self, String> {
+        let mut records = Self::new();
+        let mut err: Vec<String> = vec![];
+        for (i, line) in data.lines().enumerate() {
+            if let Err(e) = records.add_line(line) {
+                err.push(format!("line {}: {}", i + 1, e));
+            }
+        }
+        for (name, name_records) in records.exact.iter().chain(records.wildcards.iter()) {
+            let has_cname = name_records.iter().any(|(_, r)| r.record_type() == RecordType::CNAME);
+            if has_cname && name_records.len() > 1 {
+                err.push(format!("'{}' has CNAME and other records", name));
+            }
+        }
+        if ! err.is_empty() {
+            return Err(err.join(". "))
+        }
+        Ok(records)
+    }
+
+    fn add_line(&mut self, line: &str) -> Result<(), String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            return Ok(())
+        }
+        let mut fields = line.split_whitespace();
+        let name = fields.next().unwrap_or_default();
+        let mut field = fields.next().ok_or("no record type")?;
+        let mut ttl = DEFAULT_TTL;
+        if let Ok(t) = field.parse::<u32>() {
+            ttl = t;
+            field = fields.next().ok_or("no record type")?;
+        }
+        if field.eq_ignore_ascii_case("IN") {
+            field = fields.next().ok_or("no record type")?;
+        }
+        let value = fields.collect::<Vec<&str>>().join(" ");
+        if value.is_empty() {
+            return Err(String::from("no value"))
+        }
+        let rdata = match field.to_uppercase().as_str() {
+            "A" => RData::A(A(Ipv4Addr::from_str(&value).map_err(|e| e.to_string())?)),
+            "AAAA" => RData::AAAA(AAAA(Ipv6Addr::from_str(&value).map_err(|e| e.to_string())?)),
+            "CNAME" => RData::CNAME(CNAME(parse_name(&value)?)),
+            "TXT" => RData::TXT(TXT::new(vec![String::from(value.trim_matches('"'))])),
+            t => return Err(format!("unsupported record type '{}'", t)),
+        };
+        let (records, name) = match name.strip_prefix(WILDCARD_PREFIX) {
+            Some(n) => (&mut self.wildcards, n),
+            None => (&mut self.exact, name),
+        };
+        records.entry(LowerName::from(parse_name(name)?)).or_default().push((ttl, rdata));
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.exact.values().chain(self.wildcards.values()).map(|r| r.len()).sum()
+    }
+
+    // All local records of the name with it as the owner, None if the
+    // name isn't local and is forwarded
+    pub fn find(&self, name: &LowerName) -> Option<Vec<Record>> {
+        let name_records = match self.exact.get(name) {
+            Some(r) => r,
+            None => {
+                let mut parent = name.clone();
+                loop {
+                    if parent.is_root() {
+                        return None
+                    }
+                    parent = parent.base_name();
+                    if let Some(r) = self.wildcards.get(&parent) {
+                        break r
+                    }
+                }
+            },
+        };
+        let owner = Name::from(name);
+        Some(name_records.iter().map(|(ttl, rdata)| Record::from_rdata(owner.clone(), *ttl, rdata.clone())).collect())
+    }
+}
+
+fn parse_name(name: &str) -> Result<Name, String> {
+    let mut name = Name::from_str(name).map_err(|e| format!("invalid name '{}': {}", name, e))?;
+    name.set_fqdn(true);
+    Ok(name)
+}
+
+
+#[test]
+fn test_local_records_find() {
+    let records = LocalRecords::parse("
+        # comment
+        vps.home A 192.0.2.10
+        vps.home 60 IN AAAA 2001:db8::10
+        vps.home TXT \"hello world\"
+        *.dev.home 60 A 192.0.2.11
+        *.a.dev.home A 192.0.2.12
+        YouTube.com. CNAME restrict.youtube.com
+    ").unwrap();
+    assert_eq!(records.len(), 6);
+    let name = |n: &str| LowerName::from_str(n).unwrap();
+
+    let found = records.find(&name("vps.home.")).unwrap();
+    assert_eq!(found.len(), 3);
+    assert_eq!(found[1].ttl(), 60);
+    assert_eq!(found[2].data().unwrap().as_txt().unwrap().to_string(), "hello world");
+
+    let found = records.find(&name("x.b.dev.home.")).unwrap();
+    assert_eq!(found[0].name().to_string(), "x.b.dev.home.");
+    assert_eq!(found[0].data().unwrap().as_a().unwrap().0, Ipv4Addr::new(192, 0, 2, 11));
+    // The closest wildcard
+    let found = records.find(&name("x.a.dev.home.")).unwrap();
+    assert_eq!(found[0].data().unwrap().as_a().unwrap().0, Ipv4Addr::new(192, 0, 2, 12));
+    // Wildcards don't match the name itself
+    assert!(records.find(&name("dev.home.")).is_none());
+    assert!(records.find(&name("other.home.")).is_none());
+
+    let found = records.find(&name("youtube.com.")).unwrap();
+    assert_eq!(found[0].data().unwrap().as_cname().unwrap().0.to_string(), "restrict.youtube.com.");
+
+    let err = LocalRecords::parse("a.home A 1.1.1\na.home MX 10 mx.home\nc.home CNAME x.home\nc.home A 1.1.1.1").err().unwrap();
+    assert!(err.contains("line 1"), "{}", err);
+    assert!(err.contains("line 2: unsupported record type 'MX'"), "{}", err);
+    assert!(err.contains("'c.home.' has CNAME"), "{}", err);
+}
@@ -3,7 +3,26 @@ mod router;
 mod proxy_record;
 mod inner_storage;
 mod trsp_authority;
+mod reverse_authority;
+mod special_use;
+pub mod upstreams;
+mod upstream_manager;
 mod handler;
+mod response_policy;
 mod domains;
 mod domains_set;
+mod domain_rules;
+mod patterns;
+mod explain;
+mod compact_domains;
+mod prefix_set;
+mod geoip;
+mod local_records;
+mod domain_source;
+mod geosite;
+mod rpz;
+mod zapret;
+mod list_fetcher;
+mod scheduler;
+mod config;
 mod cleaner;
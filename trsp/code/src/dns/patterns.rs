@@ -0,0 +1,153 @@
+use regex::RegexSet;
+
+
+// Prefixes of rules shared by cache files and included/excluded lists
+// (same as in v2fly lists)
+pub const EXCEPTION_PREFIX: &str = "@@";
+pub const KEYWORD_PREFIX: &str = "keyword:";
+pub const REGEX_PREFIX: &str = "regexp:";
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternMatch {
+    // Indexes in keywords() and regexes()
+    Keyword(usize),
+    Regex(usize),
+}
+
+// Keyword (substring) and regex rules, checked one by one.
+// Regex rules match after build()
+#[derive(Debug)]
+pub struct Patterns {
+    keywords: Vec<String>,
+    regexes: Vec<String>,
+    regex_set: RegexSet,
+}
+
+impl Default for Patterns {
+    fn default() -> Self {
+        Self {
+            keywords: vec![],
+            regexes: vec![],
+            regex_set: RegexSet::empty(),
+        }
+    }
+}
+
+impl Patterns {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keywords.len() + self.regexes.len()
+    }
+
+    pub fn keywords(&self) -> &[String] {
+        &self.keywords
+    }
+
+    pub fn regexes(&self) -> &[String] {
+        &self.regexes
+    }
+
+    // False if the keyword is added already
+    pub fn add_keyword(&mut self, keyword: &str) -> bool {
+        if self.keywords.iter().any(|k| k == keyword) {
+            return false
+        }
+        self.keywords.push(String::from(keyword));
+        true
+    }
+
+    // False if the regex is added already
+    pub fn add_regex(&mut self, regex: &str) -> Result<bool, regex::Error> {
+        regex::Regex::new(regex)?;
+        if self.regexes.iter().any(|r| r == regex) {
+            return Ok(false)
+        }
+        self.regexes.push(String::from(regex));
+        Ok(true)
+    }
+
+    // Regexes are validated by add_regex(), the set can still exceed the size
+    // limit. Then regex rules are dropped and don't match anything
+    pub fn build(&mut self) -> Result<(), String> {
+        match RegexSet::new(&self.regexes) {
+            Ok(regex_set) => {
+                self.regex_set = regex_set;
+                Ok(())
+            },
+            Err(e) => {
+                let count = self.regexes.len();
+                self.regexes.clear();
+                self.regex_set = RegexSet::empty();
+                Err(format!("{} regex rules are skipped: {}", count, e))
+            },
+        }
+    }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        self.keywords.iter().any(|k| name.contains(k.as_str())) || self.regex_set.is_match(name)
+    }
+
+    // The first keyword or, if none, the first regex matching the name
+    pub fn find(&self, name: &str) -> Option<PatternMatch> {
+        if let Some(index) = self.keywords.iter().position(|k| name.contains(k.as_str())) {
+            return Some(PatternMatch::Keyword(index))
+        }
+        self.regex_set.matches(name).iter().next().map(PatternMatch::Regex)
+    }
+
+    // The matched rule as in the cache file
+    pub fn rule(&self, pattern: PatternMatch) -> String {
+        match pattern {
+            PatternMatch::Keyword(i) => format!("{}{}", KEYWORD_PREFIX, self.keywords[i]),
+            PatternMatch::Regex(i) => format!("{}{}", REGEX_PREFIX, self.regexes[i]),
+        }
+    }
+
+    // Rules of both are validated, the set is rebuilt if regexes are added
+    pub fn extend(&mut self, patterns: Patterns) -> Result<(), String> {
+        for keyword in &patterns.keywords {
+            self.add_keyword(keyword);
+        }
+        let count = self.regexes.len();
+        for regex in patterns.regexes {
+            if ! self.regexes.contains(&regex) {
+                self.regexes.push(regex);
+            }
+        }
+        if self.regexes.len() != count {
+            return self.build()
+        }
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.keywords.clear();
+        self.regexes.clear();
+        self.regex_set = RegexSet::empty();
+    }
+}
+
+
+#[test]
+fn test_patterns() {
+    let mut patterns = Patterns::new();
+    assert!(patterns.add_keyword("casino"));
+    assert!(!patterns.add_keyword("casino"));
+    assert!(patterns.add_regex(r"^rr[0-9]+\.oai\.com$").unwrap());
+    assert!(patterns.add_regex("(").is_err());
+    assert_eq!(patterns.len(), 2);
+    assert!(!patterns.is_match("rr12.oai.com"));
+    patterns.build().unwrap();
+
+    assert!(patterns.is_match("online-casino.com"));
+    assert!(patterns.is_match("rr12.oai.com"));
+    assert!(!patterns.is_match("rr.oai.com"));
+    assert_eq!(patterns.find("casino.rr1.oai.com"), Some(PatternMatch::Keyword(0)));
+    let pattern = patterns.find("rr1.oai.com").unwrap();
+    assert_eq!(patterns.rule(pattern), r"regexp:^rr[0-9]+\.oai\.com$");
+    assert_eq!(patterns.find("example.com"), None);
+}
@@ -0,0 +1,90 @@
+use std::net::IpAddr;
+use ipnet::IpNet;
+
+
+// Immutable set of IP prefixes, stored as sorted merged ranges of addresses
+#[derive(Debug, Default)]
+pub struct PrefixSet {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl PrefixSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn build(prefixes: &[IpNet]) -> Self {
+        let mut v4 = vec![];
+        let mut v6 = vec![];
+        for prefix in prefixes {
+            match prefix {
+                IpNet::V4(p) => v4.push((u32::from(p.network()) as u128, u32::from(p.broadcast()) as u128)),
+                IpNet::V6(p) => v6.push((u128::from(p.network()), u128::from(p.broadcast()))),
+            }
+        }
+        Self {
+            v4: merge(v4).into_iter().map(|(start, end)| (start as u32, end as u32)).collect(),
+            v6: merge(v6),
+        }
+    }
+
+    // Count of merged ranges
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match addr {
+            IpAddr::V4(a) => contains(&self.v4, u32::from(*a)),
+            IpAddr::V6(a) => match a.to_ipv4_mapped() {
+                Some(a) => contains(&self.v4, u32::from(a)),
+                None => contains(&self.v6, u128::from(*a)),
+            },
+        }
+    }
+}
+
+// IPv4 ranges are merged as u128 too
+fn merge(mut ranges: Vec<(u128, u128)>) -> Vec<(u128, u128)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        if let Some(last) = merged.last_mut() {
+            // Overlapping or adjacent
+            if last.1 == u128::MAX || start <= last.1 + 1 {
+                if end > last.1 {
+                    last.1 = end;
+                }
+                continue
+            }
+        }
+        merged.push((start, end));
+    }
+    merged
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], addr: T) -> bool {
+    // The last range starting at or before the address
+    let i = ranges.partition_point(|(start, _)| *start <= addr);
+    i > 0 && addr <= ranges[i - 1].1
+}
+
+
+#[test]
+fn test_prefix_set_contains() {
+    let prefixes: Vec<IpNet> = ["10.0.0.0/24", "10.0.1.0/24", "10.0.0.128/25", "1.1.1.1/32", "2001:db8::/32"]
+        .iter()
+        .map(|p| p.parse().unwrap())
+        .collect();
+    let set = PrefixSet::build(&prefixes);
+    // Adjacent and nested prefixes are merged
+    assert_eq!(set.len(), 3);
+    for addr in ["10.0.0.1", "10.0.1.255", "1.1.1.1", "2001:db8::1", "::ffff:10.0.0.5"] {
+        assert!(set.contains(&addr.parse().unwrap()), "{}", addr);
+    }
+    for addr in ["10.0.2.0", "9.255.255.255", "1.1.1.2", "2001:db9::1"] {
+        assert!(!set.contains(&addr.parse().unwrap()), "{}", addr);
+    }
+    assert!(!PrefixSet::new().contains(&"1.1.1.1".parse().unwrap()));
+}
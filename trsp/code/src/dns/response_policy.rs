@@ -0,0 +1,129 @@
+use std::time::Duration;
+use hickory_proto::{
+    op::ResponseCode,
+    rr::{rdata::SOA, Name, RData, Record},
+};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_server::authority::LookupError;
+
+
+// Errors of the mapping, TrspAuthority returns them as resolve error messages
+pub const POOL_EXHAUSTED: &str = "Mapped ip set is empty";
+pub const ROUTER_FAILED: &str = "Route of the mapping is not added";
+pub const STORAGE_FAILED: &str = "Mapping is not stored";
+
+// Synthesized SOA of negative answers
+const SOA_MNAME: &str = "trsp.";
+const SOA_RNAME: &str = "hostmaster.trsp.";
+const SOA_REFRESH: i32 = 1800;
+const SOA_RETRY: i32 = 900;
+const SOA_EXPIRE: i32 = 604800;
+
+
+// RCODE and authority section of answers which have no records:
+//   filtered types (AAAA without IPv6 forwarding, HTTPS/SVCB with
+//     the nodata policy)          -> NOERROR, SOA
+//   NODATA/NXDOMAIN of upstreams  -> their RCODE, their SOA or a synthesized one
+//   pool exhaustion, router and
+//     storage errors of mappings  -> SERVFAIL
+//   upstream timeouts and errors  -> SERVFAIL
+pub struct ResponsePolicy {
+    negative_ttl: u32,
+}
+
+impl ResponsePolicy {
+    pub fn new(negative_ttl: Duration) -> Self {
+        Self {
+            negative_ttl: negative_ttl.as_secs().try_into().unwrap_or(u32::MAX),
+        }
+    }
+
+    pub fn response_code(&self, error: &LookupError) -> ResponseCode {
+        match error {
+            LookupError::NameExists => ResponseCode::NoError,
+            LookupError::ResponseCode(code) => *code,
+            LookupError::ResolveError(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { response_code, .. } => *response_code,
+                ResolveErrorKind::Message(POOL_EXHAUSTED) => ResponseCode::ServFail,
+                ResolveErrorKind::Message(ROUTER_FAILED) => ResponseCode::ServFail,
+                ResolveErrorKind::Message(STORAGE_FAILED) => ResponseCode::ServFail,
+                _ => ResponseCode::ServFail,
+            },
+            _ => ResponseCode::ServFail,
+        }
+    }
+
+    // SOA for negative caching (RFC 2308), None if the answer isn't negative.
+    // The synthesized SOA is owned by the origin of the authority
+    pub fn negative_soa(&self, origin: &Name, error: Option<&LookupError>) -> Option<Record> {
+        let code = error.map(|e| self.response_code(e)).unwrap_or(ResponseCode::NoError);
+        if code != ResponseCode::NoError && code != ResponseCode::NXDomain {
+            return None
+        }
+        if let Some(LookupError::ResolveError(e)) = error {
+            if let ResolveErrorKind::NoRecordsFound { soa: Some(soa), .. } = e.kind() {
+                return Some(soa.as_ref().clone().into_record_of_rdata())
+            }
+        }
+        Some(self.synthesized_soa(origin))
+    }
+
+    fn synthesized_soa(&self, origin: &Name) -> Record {
+        let soa = SOA::new(
+            Name::from_ascii(SOA_MNAME).unwrap(),
+            Name::from_ascii(SOA_RNAME).unwrap(),
+            1,
+            SOA_REFRESH,
+            SOA_RETRY,
+            SOA_EXPIRE,
+            self.negative_ttl,
+        );
+        Record::from_rdata(origin.clone(), self.negative_ttl, RData::SOA(soa))
+    }
+}
+
+
+#[test]
+fn test_response_policy_response_code() {
+    let policy = ResponsePolicy::new(Duration::from_secs(60));
+    let origin = Name::from_ascii("example.com.").unwrap();
+
+    let error = LookupError::NameExists;
+    assert_eq!(policy.response_code(&error), ResponseCode::NoError);
+    let soa = policy.negative_soa(&origin, Some(&error)).unwrap();
+    assert_eq!(soa.name(), &origin);
+    assert_eq!(soa.ttl(), 60);
+
+    let error = LookupError::ResponseCode(ResponseCode::Refused);
+    assert_eq!(policy.response_code(&error), ResponseCode::Refused);
+    assert!(policy.negative_soa(&origin, Some(&error)).is_none());
+
+    // Empty answer
+    assert!(policy.negative_soa(&origin, None).is_some());
+}
+
+#[cfg(test)]
+fn check_mapping_error(message: &'static str) {
+    use hickory_resolver::error::ResolveError;
+
+    let policy = ResponsePolicy::new(Duration::from_secs(60));
+    let origin = Name::root();
+    let error = LookupError::from(ResolveError::from(message));
+    assert_eq!(policy.response_code(&error), ResponseCode::ServFail);
+    assert!(policy.negative_soa(&origin, Some(&error)).is_none());
+}
+
+#[test]
+fn test_response_policy_pool_exhausted() {
+    check_mapping_error(POOL_EXHAUSTED);
+}
+
+#[test]
+fn test_response_policy_router_failed() {
+    check_mapping_error(ROUTER_FAILED);
+}
+
+#[test]
+fn test_response_policy_storage_failed() {
+    check_mapping_error(STORAGE_FAILED);
+}
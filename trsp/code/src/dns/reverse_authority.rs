@@ -0,0 +1,201 @@
+use std::{
+    io,
+    net::IpAddr,
+    str::FromStr,
+    sync::Arc,
+};
+use ipnet::IpNet;
+use tokio::sync::RwLock;
+use tracing::debug;
+
+use hickory_proto::{
+    op::ResponseCode,
+    rr::{rdata::PTR, LowerName, Name, RData, Record, RecordSet, RecordType},
+};
+use hickory_server::{
+    authority::{
+        AuthLookup, Authority, LookupError, LookupOptions, LookupRecords,
+        MessageRequest, UpdateResult, ZoneType,
+    },
+    server::RequestInfo,
+};
+
+use super::inner_storage::InnerStorage;
+
+
+// Authoritative reverse zone of the mapping subnet: PTR of a mapped
+// address is the domain it's assigned to, unassigned addresses are NXDOMAIN
+pub struct ReverseAuthority {
+    origin: LowerName,
+    subnet: IpNet,
+    inner_storage: Arc<RwLock<InnerStorage>>,
+    ttl: u32,
+}
+
+impl ReverseAuthority {
+    pub fn new(origin: LowerName, subnet: IpNet, inner_storage: Arc<RwLock<InnerStorage>>, ttl: u32) -> Self {
+        Self {
+            origin,
+            subnet,
+            inner_storage,
+            ttl,
+        }
+    }
+
+    // Reverse zones are delegated by octets (nibbles for IPv6), the subnet
+    // is split to the zones of the next boundary: 10.224.128.0/17 is
+    // 128.224.10.in-addr.arpa. ... 255.224.10.in-addr.arpa.
+    pub fn zones(subnet: &IpNet) -> Vec<LowerName> {
+        let (label_bits, suffix_labels) = match subnet {
+            IpNet::V4(_) => (8, 2),
+            IpNet::V6(_) => (4, 2),
+        };
+        let labels = (subnet.prefix_len() + label_bits - 1) / label_bits;
+        let zone_prefix_len = labels * label_bits;
+        let subnets = match subnet.subnets(zone_prefix_len) {
+            Ok(s) => s,
+            Err(_) => return vec![],
+        };
+        subnets
+            .map(|s| LowerName::from(Name::from(s.network()).trim_to(labels as usize + suffix_labels)))
+            .collect()
+    }
+
+    async fn lookup_addr(&self, name: &LowerName, rtype: RecordType) -> Result<AuthLookup, LookupError> {
+        let addr = match Name::from(name).parse_arpa_name() {
+            Ok(net) if net.prefix_len() == net.max_prefix_len() => net.addr(),
+            // Empty non-terminals of the zone
+            Ok(net) if self.subnet.contains(&net) || net.contains(&self.subnet) => {
+                return Err(LookupError::for_name_exists())
+            },
+            _ => return Err(LookupError::from(ResponseCode::NXDomain)),
+        };
+        let domain = match self.owner(&addr).await {
+            Some(d) => d,
+            None => {
+                debug!("PTR {}: address is not assigned", addr);
+                return Err(LookupError::from(ResponseCode::NXDomain))
+            },
+        };
+        if rtype != RecordType::PTR {
+            return Err(LookupError::for_name_exists())
+        }
+        let record = Record::from_rdata(Name::from(name), self.ttl, RData::PTR(PTR(domain)));
+        let records = LookupRecords::new(LookupOptions::default(), Arc::new(RecordSet::from(record)));
+        Ok(AuthLookup::answers(records, None))
+    }
+
+    async fn owner(&self, addr: &IpAddr) -> Option<Name> {
+        if ! self.subnet.contains(addr) {
+            return None
+        }
+        let records_set = self.inner_storage.read().await.find_by_mapped_addr(addr)?;
+        Name::from_str(&records_set.domain).ok()
+    }
+}
+
+
+#[async_trait::async_trait]
+impl Authority for ReverseAuthority {
+    type Lookup = AuthLookup;
+
+    fn zone_type(&self) -> ZoneType {
+        ZoneType::Primary
+    }
+
+    fn is_axfr_allowed(&self) -> bool {
+        false
+    }
+
+    async fn update(&self, _update: &MessageRequest) -> UpdateResult<bool> {
+        Err(ResponseCode::NotImp)
+    }
+
+    fn origin(&self) -> &LowerName {
+        &self.origin
+    }
+
+    async fn lookup(
+        &self,
+        name: &LowerName,
+        rtype: RecordType,
+        _lookup_options: LookupOptions,
+    ) -> Result<Self::Lookup, LookupError> {
+        self.lookup_addr(name, rtype).await
+    }
+
+    async fn search(
+        &self,
+        request_info: RequestInfo<'_>,
+        lookup_options: LookupOptions,
+    ) -> Result<Self::Lookup, LookupError> {
+        self.lookup(
+            request_info.query.name(),
+            request_info.query.query_type(),
+            lookup_options,
+        )
+        .await
+    }
+
+    async fn get_nsec_records(
+        &self,
+        _name: &LowerName,
+        _lookup_options: LookupOptions,
+    ) -> Result<Self::Lookup, LookupError> {
+        Err(LookupError::from(io::Error::new(
+            io::ErrorKind::Other,
+            "Getting NSEC records is unimplemented for the reverse zone",
+        )))
+    }
+}
+
+
+#[tokio::test]
+async fn test_reverse_authority_lookup() {
+    use chrono::Utc;
+    use std::time::Duration;
+    use super::proxy_record::{ProxyRecord, ProxyRecordSet};
+
+    let subnet: IpNet = "10.224.128.0/17".parse().unwrap();
+    let zones = ReverseAuthority::zones(&subnet);
+    assert_eq!(zones.len(), 128);
+    assert_eq!(zones[0].to_string(), "128.224.10.in-addr.arpa.");
+    assert_eq!(zones[127].to_string(), "255.224.10.in-addr.arpa.");
+    let zones = ReverseAuthority::zones(&"fd00::/62".parse().unwrap());
+    assert_eq!(zones.len(), 4);
+    assert_eq!(zones[0].to_string(), "0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa.");
+
+    let name = LowerName::from_str("blocked.ru.").unwrap();
+    let mut records_set = ProxyRecordSet::new("blocked.ru.", Utc::now(), Duration::from_secs(60));
+    let record = Record::from_rdata(Name::from(&name), 60, RData::A("1.1.1.1".parse().unwrap()));
+    records_set.push(&ProxyRecord::new(
+        &record,
+        Some("1.1.1.1".parse().unwrap()),
+        Some("10.224.128.1".parse().unwrap()),
+    )).unwrap();
+    let inner_storage = Arc::new(RwLock::new(InnerStorage::new()));
+    inner_storage.write().await.upsert(&name, RecordType::A, &records_set).unwrap();
+
+    let authority = ReverseAuthority::new(zones[0].clone(), subnet, inner_storage.clone(), 60);
+    let ptr_name = LowerName::from_str("1.128.224.10.in-addr.arpa.").unwrap();
+    let lookup = authority.lookup(&ptr_name, RecordType::PTR, LookupOptions::default()).await.unwrap();
+    let records: Vec<&Record> = lookup.iter().collect();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].data().unwrap().as_ptr().unwrap().0.to_string(), "blocked.ru.");
+
+    let err = authority.lookup(&ptr_name, RecordType::A, LookupOptions::default()).await.err().unwrap();
+    assert!(matches!(err, LookupError::NameExists));
+    let unassigned = LowerName::from_str("2.128.224.10.in-addr.arpa.").unwrap();
+    let err = authority.lookup(&unassigned, RecordType::PTR, LookupOptions::default()).await.err().unwrap();
+    assert!(err.is_nx_domain());
+    let apex = LowerName::from_str("128.224.10.in-addr.arpa.").unwrap();
+    let err = authority.lookup(&apex, RecordType::SOA, LookupOptions::default()).await.err().unwrap();
+    assert!(matches!(err, LookupError::NameExists));
+
+    // The address is released when the set is replaced
+    inner_storage.write().await.upsert(&name, RecordType::A, &ProxyRecordSet::new(
+        "blocked.ru.", Utc::now(), Duration::from_secs(60),
+    )).unwrap();
+    let err = authority.lookup(&ptr_name, RecordType::PTR, LookupOptions::default()).await.err().unwrap();
+    assert!(err.is_nx_domain());
+}
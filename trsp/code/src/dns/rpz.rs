@@ -0,0 +1,414 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use tokio::net::TcpStream as TokioTcpStream;
+use tokio_stream::StreamExt;
+use tracing::{debug, info};
+
+use hickory_client::{
+    client::{AsyncClient, ClientHandle, Signer},
+    rr::{DNSClass, Name, RData, Record, RecordType, rdata::SOA},
+    tcp::TcpClientStream,
+};
+use hickory_proto::{
+    iocompat::AsyncIoTokioAsStd,
+    rr::dnssec::{tsig::TSigner, rdata::tsig::TsigAlgorithm},
+};
+
+use super::domains::Domains;
+use super::domain_source::prepare_domain_name;
+use super::patterns::EXCEPTION_PREFIX;
+
+
+const RPZ_DOMAINS_HASHSET_CAP: usize = 1024;
+const RPZ_STATE_FILENAME_SUFFIX: &str = ".soa";
+const RPZ_TIMEOUT: Duration = Duration::from_secs(30);
+const TSIG_FUDGE: u16 = 300;
+// Triggers which are not QNAME triggers
+const RPZ_NON_QNAME_LABELS: [&str; 4] = ["rpz-ip", "rpz-nsip", "rpz-nsdname", "rpz-client-ip"];
+const RPZ_PASSTHRU: &str = "rpz-passthru.";
+// RPZ "*.example.com" doesn't match the name itself
+const SUBDOMAINS_PREFIX: &str = "+.";
+
+
+// Count of RRs of the trigger, only counts > 1 are stored. The trigger is
+// removed by IXFR when its last RR is deleted
+type RrCounts = HashMap<String, u32>;
+
+
+// tsig = { name = "key-name", algorithm = "hmac-sha256", secret = "base64" }
+#[derive(Debug, Deserialize, Clone)]
+pub struct TsigConfig {
+    pub name: String,
+    #[serde(default = "default_tsig_algorithm")]
+    pub algorithm: String,
+    pub secret: String,
+}
+
+fn default_tsig_algorithm() -> String {
+    String::from("hmac-sha256")
+}
+
+
+// Serial and refresh interval of the last transferred zone version and
+// counts of RRs of triggers, stored next to the cache file
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpzState {
+    serial: u32,
+    refresh: u32,
+    checked_at: i64,
+    rr_counts: RrCounts,
+}
+
+impl RpzState {
+    fn filepath(cache_filepath: &Path) -> PathBuf {
+        let mut path = cache_filepath.as_os_str().to_os_string();
+        path.push(RPZ_STATE_FILENAME_SUFFIX);
+        PathBuf::from(path)
+    }
+
+    // "serial refresh checked_at", then "count<TAB>trigger" lines
+    async fn read(cache_filepath: &Path) -> Option<Self> {
+        let data = tokio::fs::read_to_string(Self::filepath(cache_filepath)).await.ok()?;
+        let mut lines = data.lines();
+        let mut parts = lines.next()?.split_whitespace().map(|p| p.parse::<i64>().ok());
+        let mut state = Self {
+            serial: parts.next()??.try_into().ok()?,
+            refresh: parts.next()??.try_into().ok()?,
+            checked_at: parts.next()??,
+            rr_counts: RrCounts::new(),
+        };
+        for line in lines {
+            let (count, trigger) = line.split_once('\t')?;
+            state.rr_counts.insert(String::from(trigger), count.parse().ok()?);
+        }
+        Some(state)
+    }
+
+    pub async fn write(&self, cache_filepath: &Path) -> Result<(), Box<dyn Error>> {
+        let mut data = format!("{} {} {}\n", self.serial, self.refresh, self.checked_at);
+        for (trigger, count) in &self.rr_counts {
+            data.push_str(&format!("{}\t{}\n", count, trigger));
+        }
+        tokio::fs::write(Self::filepath(cache_filepath), data).await?;
+        Ok(())
+    }
+
+    fn is_refresh_due(&self) -> bool {
+        Utc::now().timestamp() - self.checked_at >= self.refresh as i64
+    }
+}
+
+
+// Response Policy Zone transferred from the primary with AXFR/IXFR.
+// QNAME triggers become domains ("*." ones match only subdomains),
+// passthru triggers become exceptions, other triggers are skipped
+#[derive(Debug, Clone)]
+pub struct RpzZone {
+    pub primary: SocketAddr,
+    pub zone: Name,
+    pub tsig: Option<TsigConfig>,
+}
+
+impl RpzZone {
+    pub fn new(primary: SocketAddr, zone: &str, tsig: Option<TsigConfig>) -> Result<Self, Box<dyn Error>> {
+        let mut zone = Name::from_ascii(zone)?;
+        zone.set_fqdn(true);
+        Ok(Self { primary, zone, tsig })
+    }
+
+    fn create_signer(&self) -> Result<Option<Arc<Signer>>, Box<dyn Error>> {
+        let tsig = match &self.tsig {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        use base64::Engine;
+        let key = base64::engine::general_purpose::STANDARD.decode(&tsig.secret)?;
+        let signer = TSigner::new(
+            key,
+            TsigAlgorithm::from_name(Name::from_ascii(&tsig.algorithm)?),
+            Name::from_ascii(&tsig.name)?,
+            TSIG_FUDGE,
+        )?;
+        Ok(Some(Arc::new(Signer::from(signer))))
+    }
+
+    async fn connect(&self) -> Result<AsyncClient, Box<dyn Error>> {
+        let signer = self.create_signer()?;
+        let (stream, sender) = TcpClientStream::<AsyncIoTokioAsStd<TokioTcpStream>>::new(self.primary);
+        let (client, bg) = AsyncClient::with_timeout(stream, sender, RPZ_TIMEOUT, signer).await?;
+        tokio::spawn(bg);
+        Ok(client)
+    }
+
+    async fn query_soa(&self, client: &mut AsyncClient) -> Result<SOA, Box<dyn Error>> {
+        let response = client.query(self.zone.clone(), DNSClass::IN, RecordType::SOA).await?;
+        response.answers().iter()
+            .find_map(|r| r.data().and_then(RData::as_soa).cloned())
+            .ok_or_else(|| format!("No SOA for zone {}", self.zone).into())
+    }
+
+    // Returns domains, the count of skipped records and the state to write with
+    // the cache. None (use cache) while the SOA refresh interval isn't passed or
+    // the serial isn't changed. IXFR is used if the previous version is cached
+    pub async fn load(&self, cache_filepath: &Path)
+        -> Result<Option<(Domains, u64, RpzState)>, Box<dyn Error>>
+    {
+        // The state is useless without the cached version
+        let state = match cache_filepath.exists() {
+            true => RpzState::read(cache_filepath).await,
+            false => None,
+        };
+        if let Some(state) = &state {
+            if ! state.is_refresh_due() {
+                info!("RPZ {}: refresh interval isn't passed, use cache", self.zone);
+                return Ok(None)
+            }
+        }
+
+        let mut client = self.connect().await?;
+        let soa = self.query_soa(&mut client).await?;
+        let mut new_state = RpzState {
+            serial: soa.serial(),
+            refresh: soa.refresh().max(0) as u32,
+            checked_at: Utc::now().timestamp(),
+            rr_counts: RrCounts::new(),
+        };
+        if let Some(state) = &state {
+            if state.serial == soa.serial() {
+                info!("RPZ {}: serial {} isn't changed, use cache", self.zone, soa.serial());
+                new_state.rr_counts = state.rr_counts.clone();
+                new_state.write(cache_filepath).await?;
+                return Ok(None)
+            }
+        }
+
+        let mut cached_domains = Domains::new(Some(RPZ_DOMAINS_HASHSET_CAP));
+        let is_cache_ok = cached_domains.read_from_file(&cache_filepath.to_path_buf()).await.is_ok()
+            // Wildcards were cached as "*." by previous versions, transfer the zone in full
+            && ! cached_domains.iter().any(|d| d.as_str().starts_with("*."));
+        let last_soa = match &state {
+            Some(s) if is_cache_ok => Some(SOA::new(
+                self.zone.clone(), self.zone.clone(), s.serial, 0, 0, 0, 0,
+            )),
+            _ => None,
+        };
+        let is_ixfr_requested = last_soa.is_some();
+        let mut records: Vec<Record> = vec![];
+        let mut stream = client.zone_transfer(self.zone.clone(), last_soa);
+        while let Some(response) = stream.next().await {
+            records.extend_from_slice(response?.answers());
+        }
+        debug!("RPZ {}: transferred {} records", self.zone, records.len());
+
+        let (domains, errors_count, rr_counts) = match state {
+            Some(state) if is_ixfr_requested && RpzZone::is_incremental(&records) => {
+                info!("RPZ {}: apply IXFR {} -> {}", self.zone, state.serial, soa.serial());
+                self.apply_ixfr(cached_domains, state.rr_counts, &records)
+            },
+            _ => {
+                info!("RPZ {}: full zone transfer, serial {}", self.zone, soa.serial());
+                self.parse_axfr(&records)
+            },
+        };
+        new_state.rr_counts = rr_counts;
+        Ok(Some((domains, errors_count, new_state)))
+    }
+
+    // IXFR answer is SOA(new), [SOA(old), deleted..., SOA(new), added...]..., SOA(new),
+    // AXFR answer has no SOA in the second record
+    fn is_incremental(records: &[Record]) -> bool {
+        records.len() > 2 && records[1].record_type() == RecordType::SOA
+    }
+
+    fn parse_axfr(&self, records: &[Record]) -> (Domains, u64, RrCounts) {
+        let mut domains = Domains::new(Some(RPZ_DOMAINS_HASHSET_CAP));
+        let mut rr_counts = RrCounts::new();
+        let mut errors_count = 0;
+        for record in records {
+            match self.trigger_domain(record) {
+                Ok(Some(trigger)) => add_trigger(&mut domains, &mut rr_counts, &trigger),
+                Ok(None) => {},
+                Err(e) => {
+                    debug!("RPZ {}: {}", self.zone, e);
+                    errors_count += 1;
+                }
+            }
+        }
+        (domains, errors_count, rr_counts)
+    }
+
+    fn apply_ixfr(&self, mut domains: Domains, mut rr_counts: RrCounts, records: &[Record])
+        -> (Domains, u64, RrCounts)
+    {
+        let mut errors_count = 0;
+        // The first and the last records are the new SOA, then each SOA switches
+        // between deleted (after the old SOA) and added (after the new SOA) records
+        let mut is_deletion = false;
+        for record in &records[1..records.len() - 1] {
+            if record.record_type() == RecordType::SOA {
+                is_deletion = !is_deletion;
+                continue
+            }
+            match self.trigger_domain(record) {
+                Ok(Some(trigger)) => match is_deletion {
+                    true => remove_trigger(&mut domains, &mut rr_counts, &trigger),
+                    false => add_trigger(&mut domains, &mut rr_counts, &trigger),
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    debug!("RPZ {}: {}", self.zone, e);
+                    errors_count += 1;
+                }
+            }
+        }
+        (domains, errors_count, rr_counts)
+    }
+
+    // Domain of the QNAME trigger record as in the cache file ("@@" for passthru,
+    // "+." for wildcards), None for records which aren't triggers
+    fn trigger_domain(&self, record: &Record) -> Result<Option<String>, String> {
+        if matches!(record.record_type(), RecordType::SOA | RecordType::NS) {
+            return Ok(None)
+        }
+        let name = record.name();
+        // num_labels() doesn't count the wildcard label
+        let labels_count = name.iter().count();
+        let zone_labels_count = self.zone.iter().count();
+        if ! self.zone.zone_of(name) || labels_count == zone_labels_count {
+            return Ok(None)
+        }
+        let is_passthru = match record.data() {
+            Some(RData::CNAME(cname)) => cname.0.to_ascii() == RPZ_PASSTHRU,
+            _ => false,
+        };
+        let trigger = name.iter()
+            .take(labels_count - zone_labels_count)
+            .map(|l| String::from_utf8_lossy(l).to_lowercase())
+            .collect::<Vec<String>>();
+        if trigger.iter().any(|l| RPZ_NON_QNAME_LABELS.contains(&l.as_str())) {
+            return Ok(None)
+        }
+        let domain = prepare_domain_name(&trigger.join("."));
+        if domain.is_empty() {
+            return Err(format!("invalid trigger '{}'", name))
+        }
+        let domain = match domain.strip_prefix("*.") {
+            Some(d) => format!("{}{}", SUBDOMAINS_PREFIX, d),
+            None => domain,
+        };
+        match is_passthru {
+            true => Ok(Some(format!("{}{}", EXCEPTION_PREFIX, domain))),
+            false => Ok(Some(domain)),
+        }
+    }
+}
+
+fn add_trigger(domains: &mut Domains, rr_counts: &mut RrCounts, trigger: &str) {
+    let is_new = match trigger.strip_prefix(EXCEPTION_PREFIX) {
+        Some(domain) => domains.add_exception(domain),
+        None => domains.set(trigger),
+    };
+    if ! is_new {
+        *rr_counts.entry(String::from(trigger)).or_insert(1) += 1;
+    }
+}
+
+fn remove_trigger(domains: &mut Domains, rr_counts: &mut RrCounts, trigger: &str) {
+    match rr_counts.get_mut(trigger) {
+        Some(count) if *count > 2 => *count -= 1,
+        Some(_) => { rr_counts.remove(trigger); },
+        None => {
+            match trigger.strip_prefix(EXCEPTION_PREFIX) {
+                Some(domain) => domains.remove_exception(domain),
+                None => domains.remove(trigger),
+            };
+        },
+    }
+}
+
+
+#[test]
+fn test_rpz_trigger_domain() {
+    use std::str::FromStr;
+    use hickory_client::rr::rdata::CNAME;
+
+    let rpz = RpzZone::new("127.0.0.1:53".parse().unwrap(), "rpz.example", None).unwrap();
+    let record = |name: &str, target: &str| Record::from_rdata(
+        Name::from_str(name).unwrap(),
+        300,
+        RData::CNAME(CNAME(Name::from_str(target).unwrap())),
+    );
+    let soa = |serial: u32| Record::from_rdata(
+        Name::from_str("rpz.example.").unwrap(),
+        300,
+        RData::SOA(SOA::new(rpz.zone.clone(), rpz.zone.clone(), serial, 3600, 600, 86400, 60)),
+    );
+
+    let a = |name: &str, addr: &str| Record::from_rdata(
+        Name::from_str(name).unwrap(),
+        300,
+        RData::A(addr.parse().unwrap()),
+    );
+
+    assert_eq!(rpz.trigger_domain(&record("Bad.com.rpz.example.", ".")), Ok(Some("bad.com".into())));
+    assert_eq!(rpz.trigger_domain(&record("*.bad.org.rpz.example.", "*.")), Ok(Some("+.bad.org".into())));
+    assert_eq!(rpz.trigger_domain(&record("ok.com.rpz.example.", "rpz-passthru.")), Ok(Some("@@ok.com".into())));
+    assert_eq!(rpz.trigger_domain(&record("32.1.0.0.10.rpz-ip.rpz.example.", ".")), Ok(None));
+    assert_eq!(rpz.trigger_domain(&record("other.zone.", ".")), Ok(None));
+
+    let axfr = vec![
+        soa(1),
+        record("a.com.rpz.example.", "."),
+        record("*.b.com.rpz.example.", "."),
+        record("ok.b.com.rpz.example.", "rpz-passthru."),
+        // Local data of two RRs
+        a("d.com.rpz.example.", "10.0.0.1"),
+        a("d.com.rpz.example.", "10.0.0.2"),
+        soa(1),
+    ];
+    assert!(!RpzZone::is_incremental(&axfr));
+    let (domains, errors_count, rr_counts) = rpz.parse_axfr(&axfr);
+    assert_eq!(errors_count, 0);
+    assert_eq!(domains.count(), 3);
+    assert!(domains.get("+.b.com").is_some());
+    assert!(domains.get_exception("ok.b.com").is_some());
+    assert_eq!(rr_counts.get("d.com"), Some(&2));
+
+    let ixfr = vec![
+        soa(2),
+        soa(1),
+        record("a.com.rpz.example.", "."),
+        a("d.com.rpz.example.", "10.0.0.1"),
+        soa(2),
+        record("c.com.rpz.example.", "."),
+        soa(2),
+    ];
+    assert!(RpzZone::is_incremental(&ixfr));
+    let (domains, _, rr_counts) = rpz.apply_ixfr(domains, rr_counts, &ixfr);
+    assert!(domains.get("a.com").is_none());
+    assert!(domains.get("+.b.com").is_some());
+    assert!(domains.get("c.com").is_some());
+    // One RR of the trigger is left
+    assert!(domains.get("d.com").is_some());
+    assert!(rr_counts.is_empty());
+
+    let ixfr = vec![
+        soa(3),
+        soa(2),
+        a("d.com.rpz.example.", "10.0.0.2"),
+        record("ok.b.com.rpz.example.", "rpz-passthru."),
+        soa(3),
+        soa(3),
+    ];
+    let (domains, _, _) = rpz.apply_ixfr(domains, rr_counts, &ixfr);
+    assert!(domains.get("d.com").is_none());
+    assert!(domains.get_exception("ok.b.com").is_none());
+}
@@ -0,0 +1,291 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+    time::Duration,
+};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use tokio::{
+    sync::Mutex,
+    task::JoinHandle,
+};
+use tracing::{error, info};
+
+use crate::options::Options;
+use super::domains_set::{ArcDomainsSet, DomainsSet};
+use super::domain_source::DomainSource;
+
+
+const SCHEDULER_TICK: Duration = Duration::from_secs(10);
+const SOURCES_STATUS_FILENAME: &str = "sources_status.txt";
+// Retry delay stops doubling after this count of failures
+const MAX_BACKOFF_SHIFT: u32 = 16;
+
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceStatus {
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    // Consecutive failures
+    pub failures_count: u32,
+    // None if the refresh is disabled
+    pub next_refresh: Option<DateTime<Utc>>,
+}
+
+
+// Refreshes due sources and swaps in the domains set rebuilt from the caches.
+// Status of sources is written to "sources_status.txt" in the workdir
+pub struct RefreshScheduler {
+    workdir: PathBuf,
+    domains_set: ArcDomainsSet,
+    // Reload and refresh don't import domains at the same time
+    import_lock: Arc<Mutex<()>>,
+    // The set stored by the last refresh, another one is a reload
+    refreshed_domains_set: Weak<DomainsSet>,
+    statuses: HashMap<String, SourceStatus>,
+    default_interval_secs: u64,
+    jitter_secs: u64,
+    retry_secs: u64,
+}
+
+impl RefreshScheduler {
+    pub fn new(
+        options: &Options,
+        workdir: &Path,
+        domains_set: ArcDomainsSet,
+        import_lock: Arc<Mutex<()>>,
+    ) -> Self {
+        Self {
+            workdir: workdir.to_path_buf(),
+            refreshed_domains_set: Arc::downgrade(&domains_set.load_full()),
+            domains_set,
+            import_lock,
+            statuses: HashMap::new(),
+            default_interval_secs: options.dns_refresh_interval_secs,
+            jitter_secs: options.dns_refresh_jitter_secs,
+            retry_secs: options.dns_refresh_retry_secs,
+        }
+    }
+
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SCHEDULER_TICK);
+            loop {
+                interval.tick().await;
+                self.refresh_due_sources().await;
+            }
+        })
+    }
+
+    fn next_refresh(&self, source: &DomainSource, failures_count: u32) -> Option<DateTime<Utc>> {
+        let jitter_secs = rand::thread_rng().gen_range(0..=self.jitter_secs);
+        let delay_secs = refresh_delay_secs(
+            source.refresh_interval_secs.unwrap_or(self.default_interval_secs),
+            jitter_secs,
+            self.retry_secs,
+            failures_count,
+        )?;
+        Some(Utc::now() + chrono::Duration::seconds(delay_secs as i64))
+    }
+
+    async fn refresh_due_sources(&mut self) {
+        let domains_set = self.domains_set.load_full();
+        // Sources are loaded again on reload, their statuses start over
+        if ! Weak::ptr_eq(&self.refreshed_domains_set, &Arc::downgrade(&domains_set)) {
+            self.statuses.clear();
+            self.refreshed_domains_set = Arc::downgrade(&domains_set);
+        }
+        let now = Utc::now();
+        let mut due_sources = vec![];
+        for source in &domains_set.sources {
+            if ! self.statuses.contains_key(&source.name) {
+                let status = SourceStatus {
+                    next_refresh: self.next_refresh(source, 0),
+                    ..Default::default()
+                };
+                self.statuses.insert(source.name.clone(), status);
+            }
+            if self.statuses[&source.name].next_refresh.map_or(false, |t| t <= now) {
+                due_sources.push(source);
+            }
+        }
+        if due_sources.is_empty() {
+            return
+        }
+
+        let import_lock = self.import_lock.clone();
+        let _import_guard = import_lock.lock().await;
+        // Domains set was reloaded while waiting for the lock
+        if ! Arc::ptr_eq(&domains_set, &*self.domains_set.load()) {
+            return
+        }
+        let mut refreshed = vec![];
+        for source in due_sources {
+            match source.refresh(&self.workdir, &domains_set.fetcher).await.map_err(|e| e.to_string()) {
+                Ok(_) => refreshed.push(source),
+                Err(e) => {
+                    error!("Refresh source '{}' error: {}", source.name, e);
+                    self.update_status(source, Err(e));
+                },
+            }
+        }
+
+        if ! refreshed.is_empty() {
+            let mut new_domains_set = DomainsSet::new(&self.workdir);
+            new_domains_set.sources = domains_set.sources.clone();
+            new_domains_set.fetcher = domains_set.fetcher.clone();
+            new_domains_set.mmap_imported_domains = domains_set.mmap_imported_domains;
+            new_domains_set.geoip = domains_set.geoip.clone();
+            new_domains_set.local_records = domains_set.local_records.clone();
+            // Refreshed sources succeed only when their data is in service
+            match new_domains_set.import_cached_domains().await {
+                Ok(report) => {
+                    let new_domains_set = Arc::new(new_domains_set);
+                    self.refreshed_domains_set = Arc::downgrade(&new_domains_set);
+                    self.domains_set.store(new_domains_set);
+                    if ! report.is_ok() {
+                        error!("Domains refreshed with errors: {}", report);
+                    } else {
+                        info!("Domains refreshed");
+                    }
+                    for source in refreshed {
+                        let res = match report.source_error(&source.name) {
+                            Some(e) => Err(String::from(e)),
+                            None => Ok(()),
+                        };
+                        self.update_status(source, res);
+                    }
+                },
+                Err(e) => {
+                    error!("Domains are not refreshed, previous ones are kept: {}", e);
+                    for source in refreshed {
+                        self.update_status(source, Err(format!("Domains are not refreshed: {}", e)));
+                    }
+                },
+            }
+        }
+        if let Err(e) = self.write_status_file().await {
+            error!("Error while writing sources status: {}", e);
+        }
+    }
+
+    fn update_status(&mut self, source: &DomainSource, res: Result<(), String>) {
+        let mut status = self.statuses.remove(&source.name).unwrap_or_default();
+        match res {
+            Ok(_) => {
+                status.last_success = Some(Utc::now());
+                status.failures_count = 0;
+            },
+            Err(e) => {
+                status.last_failure = Some(Utc::now());
+                status.last_error = Some(e);
+                status.failures_count += 1;
+            },
+        }
+        status.next_refresh = self.next_refresh(source, status.failures_count);
+        if let Some(next_refresh) = status.next_refresh {
+            info!("Source '{}' next refresh at {}", source.name, next_refresh.to_rfc3339());
+        }
+        self.statuses.insert(source.name.clone(), status);
+    }
+
+    // Tab separated: name, last success, last failure, failures count, next refresh, last error
+    async fn write_status_file(&self) -> Result<(), std::io::Error> {
+        let time = |t: &Option<DateTime<Utc>>| t.map_or(String::from("-"), |t| t.to_rfc3339());
+        let mut names: Vec<&String> = self.statuses.keys().collect();
+        names.sort();
+        let mut data = String::new();
+        for name in names {
+            let status = &self.statuses[name];
+            data.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\n",
+                name,
+                time(&status.last_success),
+                time(&status.last_failure),
+                status.failures_count,
+                time(&status.next_refresh),
+                status.last_error.as_deref().unwrap_or("-"),
+            ));
+        }
+        tokio::fs::write(self.workdir.join(SOURCES_STATUS_FILENAME), data).await
+    }
+}
+
+// Interval with jitter after success, exponential backoff (capped by the interval)
+// after failures. None if the refresh is disabled
+fn refresh_delay_secs(interval_secs: u64, jitter_secs: u64, retry_secs: u64, failures_count: u32)
+    -> Option<u64>
+{
+    if interval_secs == 0 {
+        return None
+    }
+    if failures_count == 0 {
+        return Some(interval_secs + jitter_secs)
+    }
+    let backoff_secs = retry_secs << (failures_count - 1).min(MAX_BACKOFF_SHIFT);
+    Some(backoff_secs.min(interval_secs))
+}
+
+
+#[test]
+fn test_scheduler_refresh_delay() {
+    assert_eq!(refresh_delay_secs(0, 10, 60, 0), None);
+    assert_eq!(refresh_delay_secs(3600, 10, 60, 0), Some(3610));
+    assert_eq!(refresh_delay_secs(3600, 10, 60, 1), Some(60));
+    assert_eq!(refresh_delay_secs(3600, 10, 60, 3), Some(240));
+    assert_eq!(refresh_delay_secs(3600, 10, 60, 10), Some(3600));
+    assert_eq!(refresh_delay_secs(3600, 10, 60, 100), Some(3600));
+}
+
+#[tokio::test]
+async fn test_scheduler_refresh_with_failed_source() {
+    use clap::Parser;
+    use arc_swap::ArcSwap;
+    use super::domain_source::{SourceFormat, SourceLocation, SourceRole};
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let workdir = tempdir.path().to_path_buf();
+    let included_path = workdir.join("included_source.txt");
+    std::fs::write(&included_path, "blocked.ru\n").unwrap();
+
+    let mut domains_set = DomainsSet::new(&workdir);
+    domains_set.sources = vec![
+        DomainSource::new(
+            "included",
+            SourceLocation::Path(included_path),
+            SourceFormat::Plain,
+            SourceRole::Include,
+        ),
+        // Neither the list nor its cache file exist
+        DomainSource::new(
+            "missing",
+            SourceLocation::Path(workdir.join("missing_source.txt")),
+            SourceFormat::Plain,
+            SourceRole::Include,
+        ),
+    ];
+    let domains_set = Arc::new(ArcSwap::from_pointee(domains_set));
+    let options = Options::parse_from(["trsp"]);
+    let mut scheduler = RefreshScheduler::new(&options, &workdir, domains_set.clone(), Arc::new(Mutex::new(())));
+    for name in ["included", "missing"] {
+        let status = SourceStatus { next_refresh: Some(Utc::now()), ..Default::default() };
+        scheduler.statuses.insert(String::from(name), status);
+    }
+    scheduler.refresh_due_sources().await;
+
+    // Refreshed data is in service despite the failed source
+    assert!(domains_set.load().is_domain_blocked("blocked.ru").await);
+    assert!(scheduler.statuses["included"].last_success.is_some());
+    assert!(scheduler.statuses["missing"].last_success.is_none());
+    assert_eq!(scheduler.statuses["missing"].failures_count, 1);
+
+    // Statuses of reloaded sources start over
+    let mut reloaded_domains_set = DomainsSet::new(&workdir);
+    reloaded_domains_set.sources = domains_set.load().sources.clone();
+    domains_set.store(Arc::new(reloaded_domains_set));
+    scheduler.refresh_due_sources().await;
+    assert!(scheduler.statuses["included"].last_success.is_none());
+    assert_eq!(scheduler.statuses["missing"].failures_count, 0);
+}
@@ -1,8 +1,10 @@
 use crate::dns::handler::Handler;
 use crate::options::Options;
+use arc_swap::ArcSwap;
 use tokio::{
     task::JoinHandle,
     net::{TcpListener, UdpSocket},
+    sync::{oneshot, Mutex, RwLock},
 };
 use std::{
     error::Error,
@@ -11,18 +13,49 @@ use std::{
     time::Duration,
     str::FromStr,
 };
+use hickory_proto::rr::LowerName;
 use hickory_server::server::ServerFuture;
 use reqwest::Url;
 
-use tracing::error;
+use tracing::{error, info, warn};
 
+use super::config::DnsConfig;
 use super::domains_set::{ArcDomainsSet, DomainsSet};
+use super::explain::{AddrMatch, Explanation};
+use super::geoip::GeoIp;
+use super::local_records::LocalRecords;
+use super::domain_source::{DomainSource, SourceFormat, SourceGuards, SourceLocation, SourceRole};
+use super::inner_storage::InnerStorage;
+use super::list_fetcher::ListFetcher;
+use super::router::{Router, Iptables, VpnSubnet};
+use super::scheduler::RefreshScheduler;
+use super::upstream_manager::UpstreamsMonitor;
+
+
+const MAPPINGS_SNAPSHOT_FILENAME: &str = "mappings_snapshot.txt";
+const DEFAULT_MAX_SHRINK_PERCENT: f64 = 50.0;
+
+
+type ShutdownReply = oneshot::Sender<Result<(), String>>;
+
+
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    pub drain_timed_out: bool,
+    pub errors: Vec<String>,
+}
 
 
 pub struct DnsServer {
     options: Options,
     workdir: PathBuf,
     domains_set: Option<ArcDomainsSet>,
+    router: Option<Arc<dyn Router>>,
+    inner_storage: Arc<RwLock<InnerStorage>>,
+    shutdown_tx: Option<oneshot::Sender<ShutdownReply>>,
+    import_lock: Arc<Mutex<()>>,
+    scheduler: Option<JoinHandle<()>>,
+    upstreams_monitor: Option<JoinHandle<()>>,
 }
 
 
@@ -32,18 +65,74 @@ impl<'a> DnsServer {
             options: options.clone(),
             workdir: workdir.clone(),
             domains_set: None,
+            router: None,
+            inner_storage: Arc::new(RwLock::new(InnerStorage::new())),
+            shutdown_tx: None,
+            import_lock: Arc::new(Mutex::new(())),
+            scheduler: None,
+            upstreams_monitor: None,
         }
     }
 
-    fn create_domains_set(&self) -> Result<DomainsSet, Box<dyn Error>> {
+    fn create_domains_set(&self, config: &DnsConfig) -> Result<DomainsSet, Box<dyn Error>> {
         let mut domains_set = DomainsSet::new(&self.workdir);
-        domains_set.zapret_domains_csv_url = Some(Url::from_str(
-            self.options.dns_zapret_blocked_domains_csv.as_str()
-        )?);
-        domains_set.zapret_nxdomains_txt_url = Some(Url::from_str(
-            self.options.dns_zapret_blocked_nxdomains_txt.as_str()
-        )?);
-        return Ok(domains_set)
+        domains_set.sources = self.create_domain_sources(config)?;
+        domains_set.fetcher = Arc::new(ListFetcher::new(&self.options)?);
+        domains_set.mmap_imported_domains = self.options.dns_mmap_imported_domains;
+        domains_set.geoip = GeoIp::from_config(&config.geoip)?.map(Arc::new);
+        if let Some(filepath) = &config.local_records {
+            let local_records = LocalRecords::load(filepath)?;
+            info!("Loaded {} local records", local_records.len());
+            domains_set.local_records = Arc::new(local_records);
+        }
+        Ok(domains_set)
+    }
+
+    // Sources from the config file, zapret lists from options if there are none
+    fn create_domain_sources(&self, config: &DnsConfig) -> Result<Vec<DomainSource>, Box<dyn Error>> {
+        if ! config.sources.is_empty() {
+            let mut sources = vec![];
+            for source_config in &config.sources {
+                let source = DomainSource::from_config(source_config)?;
+                if sources.iter().any(|s: &DomainSource| s.name == source.name) {
+                    return Err(format!("Duplicate source name '{}'", source.name).into())
+                }
+                sources.push(source);
+            }
+            return Ok(sources)
+        }
+        let mut zapret_domains = DomainSource::new(
+            "zapret_domains",
+            SourceLocation::Url(Url::from_str(
+                self.options.dns_zapret_blocked_domains_csv.as_str()
+            )?),
+            SourceFormat::ZapretCsv,
+            SourceRole::Include,
+        );
+        // Truncated dump.csv from the mirror is not imported
+        zapret_domains.guards = SourceGuards {
+            max_shrink_percent: Some(DEFAULT_MAX_SHRINK_PERCENT),
+            ..Default::default()
+        };
+        zapret_domains.ips = self.options.dns_zapret_ips;
+        Ok(vec![
+            zapret_domains,
+            DomainSource::new(
+                "zapret_nxdomains",
+                SourceLocation::Url(Url::from_str(
+                    self.options.dns_zapret_blocked_nxdomains_txt.as_str()
+                )?),
+                SourceFormat::Plain,
+                SourceRole::Exclude,
+            ),
+        ])
+    }
+
+    fn create_router(&self) -> Result<Arc<dyn Router>, Box<dyn Error>> {
+        let vpn_subnet = VpnSubnet::V4(self.options.dns_vpn_ipv4_subnet);
+        let router = Iptables::new(None, vpn_subnet, false, self.options.dns_mock_router);
+        router.init()?;
+        Ok(Arc::new(router))
     }
 
     // async fn get_records(&self) -> Result<(), Box<dyn Error>> {
@@ -53,14 +142,38 @@ impl<'a> DnsServer {
     pub async fn start(&mut self)
         -> Result<JoinHandle<()>, Box<dyn Error>>
     {
-        let domains_set = Arc::new(self.create_domains_set()?);
+        let config = DnsConfig::load(&self.options.config)?;
+        let domains_set = self.create_domains_set(&config)?;
+        match domains_set.import_domains().await {
+            Ok(report) if ! report.is_ok() => error!("Error while loading blocked domains data: {}", report),
+            Ok(_) => {},
+            Err(e) => error!("Error while loading blocked domains data: {}", e),
+        }
+        let domains_set = Arc::new(ArcSwap::from_pointee(domains_set));
         self.domains_set = Some(domains_set.clone());
 
-        if let Err(e) = domains_set.import_domains().await {
-            error!("Error while loading blocked domains data: {}", e)
-        }
+        let scheduler = RefreshScheduler::new(
+            &self.options,
+            &self.workdir,
+            domains_set.clone(),
+            self.import_lock.clone(),
+        );
+        self.scheduler = Some(scheduler.spawn());
 
-        let handler = Handler::new(&self.options, domains_set)?;
+        let router = self.create_router()?;
+        self.router = Some(router.clone());
+
+        let upstreams_monitor = UpstreamsMonitor::new(&self.workdir);
+        let handler = Handler::new(
+            &self.options,
+            domains_set,
+            router,
+            self.inner_storage.clone(),
+            &config.zones,
+            &config.upstreams,
+            &upstreams_monitor,
+        )?;
+        self.upstreams_monitor = Some(upstreams_monitor.spawn());
 
         let mut server = ServerFuture::new(handler);
 
@@ -77,24 +190,141 @@ impl<'a> DnsServer {
             );
         }
 
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<ShutdownReply>();
+        self.shutdown_tx = Some(shutdown_tx);
+
         //let dns_join = tokio::spawn(server.block_until_done());
         let dns_join = tokio::spawn({
             async move {
-                server.block_until_done().await.unwrap();
+                tokio::select! {
+                    res = server.block_until_done() => {
+                        res.unwrap();
+                    },
+                    Ok(reply) = &mut shutdown_rx => {
+                        // Stop accepting new requests and wait for in-flight ones
+                        let res = server.shutdown_gracefully().await;
+                        let _ = reply.send(res.map_err(|e| e.to_string()));
+                    },
+                }
             }
         });
 
         Ok(dns_join)
     }
 
-    pub async fn reload(&mut self) -> Result<(), Box<dyn Error>> {
-        if let Some(domains_set) = &self.domains_set {
-            domains_set.clear().await;
-            if let Err(e) = domains_set.import_domains().await {
-                error!("Error while loading blocked domains data: {}", e);
+    // The new domains set (with sources from the re-read config) is imported
+    // aside and swapped in, the current one stays in service meanwhile.
+    // The import is done by DomainsReload without the server
+    pub fn prepare_reload(&self) -> Result<Option<DomainsReload>, Box<dyn Error>> {
+        let current_domains_set = match &self.domains_set {
+            Some(s) => s.clone(),
+            None => return Ok(None),
+        };
+        let config = DnsConfig::load(&self.options.config)?;
+        let domains_set = self.create_domains_set(&config)?;
+        Ok(Some(DomainsReload {
+            domains_set,
+            current_domains_set,
+            import_lock: self.import_lock.clone(),
+        }))
+    }
+
+    // Why the name is or isn't routed. Without the started server, domains are
+    // loaded from the cache files and mappings from the last snapshot.
+    // Original addresses of mappings are checked against prefixes and GeoIP
+    pub async fn explain(&self, name: &str) -> Result<Explanation, Box<dyn Error>> {
+        let domains_set = match &self.domains_set {
+            Some(domains_set) => domains_set.load_full(),
+            None => {
+                let config = DnsConfig::load(&self.options.config)?;
+                let domains_set = self.create_domains_set(&config)?;
+                match domains_set.import_cached_domains().await {
+                    Ok(report) if ! report.is_ok() => warn!("Error while loading cached domains: {}", report),
+                    Ok(_) => {},
+                    Err(e) => warn!("Error while loading cached domains: {}", e),
+                }
+                Arc::new(domains_set)
+            },
+        };
+        let mut explanation = domains_set.explain(name).await;
+        if self.domains_set.is_some() {
+            let name = LowerName::from_str(&format!("{}.", explanation.name))?;
+            explanation.mappings = self.inner_storage.read().await.mappings(&name);
+        } else {
+            let snapshot_filepath = self.workdir.join(MAPPINGS_SNAPSHOT_FILENAME);
+            if snapshot_filepath.exists() {
+                let res = InnerStorage::read_snapshot_mappings(&snapshot_filepath, &explanation.name).await;
+                match res {
+                    Ok(mappings) => explanation.mappings = mappings,
+                    Err(e) => explanation.errors.push(format!("Mappings snapshot: {}", e)),
+                }
             }
         }
-        Ok(())
+        let mut addr_matches = vec![];
+        for mapping in &explanation.mappings {
+            if let Some(reason) = domains_set.addr_block_reason(&mapping.original_addr).await {
+                addr_matches.push(AddrMatch { addr: mapping.original_addr, reason });
+            }
+        }
+        explanation.add_addr_matches(addr_matches);
+        Ok(explanation)
+    }
+
+    // Stop listeners, drain in-flight requests, persist mappings
+    // and remove routing rules (unless dns_keep_rules_on_exit is set).
+    // Only the drain is limited by the timeout, the snapshot and the
+    // cleanup run after it anyway
+    pub async fn shutdown(&mut self, drain_timeout: Duration) -> ShutdownReport {
+        let mut report = ShutdownReport::default();
+        let err = &mut report.errors;
+
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.abort();
+        }
+        if let Some(upstreams_monitor) = self.upstreams_monitor.take() {
+            upstreams_monitor.abort();
+        }
+
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            info!("Stopping DNS listeners");
+            let (reply_tx, reply_rx) = oneshot::channel();
+            if shutdown_tx.send(reply_tx).is_ok() {
+                match tokio::time::timeout(drain_timeout, reply_rx).await {
+                    Ok(Ok(Ok(()))) => info!("DNS listeners stopped"),
+                    Ok(Ok(Err(e))) => err.push(format!("DNS server shutdown error: {}", e)),
+                    Ok(Err(_)) => warn!("DNS server task exited before shutdown"),
+                    Err(_) => {
+                        error!("In-flight requests are not drained in {:?}", drain_timeout);
+                        report.drain_timed_out = true;
+                    },
+                }
+            } else {
+                warn!("DNS server task exited before shutdown");
+            }
+        }
+
+        let snapshot_filepath = self.workdir.join(MAPPINGS_SNAPSHOT_FILENAME);
+        let inner_storage = self.inner_storage.read().await;
+        match inner_storage.write_snapshot(&snapshot_filepath).await {
+            Ok(_) => info!(
+                "Mappings snapshot written ({} records): {}",
+                inner_storage.len(), snapshot_filepath.as_path().display(),
+            ),
+            Err(e) => err.push(format!("Error while writing mappings snapshot: {}", e)),
+        }
+        drop(inner_storage);
+
+        if let Some(router) = self.router.take() {
+            if self.options.dns_keep_rules_on_exit {
+                warn!("Keep routing rules on exit");
+            } else if let Err(e) = router.cleanup() {
+                err.push(format!("Error while cleanup routing rules: {}", e));
+            } else {
+                info!("Routing rules removed");
+            }
+        }
+
+        report
     }
 
     // pub async fn import_domains(&mut self) -> Result<(), Box<dyn Error>> {
@@ -106,3 +336,26 @@ impl<'a> DnsServer {
     // }
 }
 
+
+pub struct DomainsReload {
+    domains_set: DomainsSet,
+    current_domains_set: ArcDomainsSet,
+    import_lock: Arc<Mutex<()>>,
+}
+
+impl DomainsReload {
+    pub async fn run(self) -> Result<(), Box<dyn Error>> {
+        let _import_guard = self.import_lock.lock().await;
+        let report = match self.domains_set.import_domains().await {
+            Ok(report) => report,
+            Err(e) => return Err(format!("Domains are not reloaded, previous ones are kept: {}", e).into()),
+        };
+        self.current_domains_set.store(Arc::new(self.domains_set));
+        if ! report.is_ok() {
+            error!("Domains reloaded with errors, failed sources are loaded from the cache: {}", report);
+        } else {
+            info!("Domains reloaded");
+        }
+        Ok(())
+    }
+}
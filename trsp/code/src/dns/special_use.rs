@@ -0,0 +1,73 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+use hickory_proto::{
+    op::ResponseCode,
+    rr::{rdata::{A, AAAA}, LowerName, Name, RData, Record, RecordType},
+};
+use hickory_server::authority::LookupError;
+
+
+const LOOPBACK_TTL: u32 = 86400;
+
+// Names that must not be forwarded (RFC 6761, RFC 6762, RFC 7686,
+// RFC 8375) and reverse zones of private networks (RFC 6303). Zones of
+// the config and the mapping subnet are more specific and go first
+const LOOPBACK_ZONES: &[&str] = &["localhost."];
+const NXDOMAIN_ZONES: &[&str] = &[
+    "invalid.",
+    "test.",
+    "local.",
+    "onion.",
+    "home.arpa.",
+    "10.in-addr.arpa.",
+    "16.172.in-addr.arpa.", "17.172.in-addr.arpa.", "18.172.in-addr.arpa.", "19.172.in-addr.arpa.",
+    "20.172.in-addr.arpa.", "21.172.in-addr.arpa.", "22.172.in-addr.arpa.", "23.172.in-addr.arpa.",
+    "24.172.in-addr.arpa.", "25.172.in-addr.arpa.", "26.172.in-addr.arpa.", "27.172.in-addr.arpa.",
+    "28.172.in-addr.arpa.", "29.172.in-addr.arpa.", "30.172.in-addr.arpa.", "31.172.in-addr.arpa.",
+    "168.192.in-addr.arpa.",
+    "254.169.in-addr.arpa.",
+    "d.f.ip6.arpa.",
+    "8.e.f.ip6.arpa.", "9.e.f.ip6.arpa.", "a.e.f.ip6.arpa.", "b.e.f.ip6.arpa.",
+];
+
+
+// Answer of the special-use name, None if the name isn't special
+pub fn answer(name: &LowerName, rtype: RecordType) -> Option<Result<Vec<Record>, LookupError>> {
+    if is_in_zones(name, LOOPBACK_ZONES) {
+        let rdata = match rtype {
+            RecordType::A => RData::A(A(Ipv4Addr::LOCALHOST)),
+            RecordType::AAAA => RData::AAAA(AAAA(Ipv6Addr::LOCALHOST)),
+            _ => return Some(Err(LookupError::for_name_exists())),
+        };
+        return Some(Ok(vec![Record::from_rdata(Name::from(name), LOOPBACK_TTL, rdata)]))
+    }
+    if is_in_zones(name, NXDOMAIN_ZONES) {
+        return Some(Err(LookupError::from(ResponseCode::NXDomain)))
+    }
+    None
+}
+
+fn is_in_zones(name: &LowerName, zones: &[&str]) -> bool {
+    zones.iter().any(|z| LowerName::from_str(z).map_or(false, |z| z.zone_of(name)))
+}
+
+
+#[test]
+fn test_special_use_answer() {
+    let name = |n: &str| LowerName::from_str(n).unwrap();
+
+    let records = answer(&name("localhost."), RecordType::A).unwrap().unwrap();
+    assert_eq!(records[0].data().unwrap().as_a().unwrap().0, Ipv4Addr::LOCALHOST);
+    let records = answer(&name("app.LocalHost."), RecordType::AAAA).unwrap().unwrap();
+    assert_eq!(records[0].name().to_string(), "app.localhost.");
+    assert!(matches!(answer(&name("localhost."), RecordType::MX), Some(Err(LookupError::NameExists))));
+
+    for n in ["invalid.", "printer.local.", "x.onion.", "nas.home.arpa.", "1.0.17.172.in-addr.arpa."] {
+        assert!(answer(&name(n), RecordType::A).unwrap().err().unwrap().is_nx_domain(), "{}", n);
+    }
+    for n in ["example.com.", "localhost.com.", "1.0.32.172.in-addr.arpa.", "arpa."] {
+        assert!(answer(&name(n), RecordType::A).is_none(), "{}", n);
+    }
+}
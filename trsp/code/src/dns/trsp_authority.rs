@@ -1,9 +1,8 @@
 use std::{
     io,
     time::Instant,
-    str::FromStr,
     collections::VecDeque,
-    sync::Arc,
+    sync::{Arc, Weak},
     net::{Ipv4Addr, IpAddr},
 };
 
@@ -17,7 +16,10 @@ use hickory_client::{
     op::ResponseCode,
 };
 
-use hickory_proto::rr::{LowerName, RecordType, Name};
+use hickory_proto::rr::{
+    LowerName, RecordType, Name, RData,
+    rdata::{A, HTTPS, SVCB, svcb::{IpHint, SvcParamKey, SvcParamValue}},
+};
 
 use hickory_server::{
     authority::{
@@ -25,43 +27,76 @@ use hickory_server::{
         MessageRequest, UpdateResult, ZoneType,
     },
     server::RequestInfo,
-    store::forwarder::{ForwardLookup, ForwardConfig},
+    store::forwarder::ForwardLookup,
     proto::{op::Query, rr::Record},
 };
 
 use hickory_resolver::{
     lookup::Lookup,
-    TokioAsyncResolver,
-    config::ResolverConfig,
     error::{ResolveError, ResolveErrorKind},
 };
 
 use std::error::Error;
-use crate::options::Options;
+use crate::options::{Options, SvcbPolicy};
 
 use super::{
     domains_set::ArcDomainsSet,
     inner_storage::InnerStorage,
     proxy_record::{ProxyRecordSet, ProxyRecord},
-    router::{Router, Iptables, VpnSubnet}
+    response_policy::{POOL_EXHAUSTED, ROUTER_FAILED, STORAGE_FAILED},
+    special_use,
+    upstream_manager::UpstreamManager,
+    router::Router,
 };
 
 
+// Local CNAMEs followed before the target is forwarded
+const MAX_LOCAL_CNAME_CHAIN: usize = 8;
+
+
+// Free addresses of the mapping subnet
+pub type MappingPool = Arc<RwLock<VecDeque<Ipv4Addr>>>;
+
+pub fn new_mapping_pool(subnet: Ipv4Net) -> MappingPool {
+    Arc::new(RwLock::new(VecDeque::from_iter(subnet.hosts())))
+}
+
+// Authorities of the root and of forwarded zones by their origins
+pub type ZoneAuthorities = Arc<std::sync::RwLock<Vec<(LowerName, Weak<TrspAuthority>)>>>;
+
+
+// Shared by the authorities of the root and of conditionally forwarded zones
+#[derive(Clone)]
+pub struct SharedContext {
+    pub router: Arc<dyn Router>,
+    pub inner_storage: Arc<RwLock<InnerStorage>>,
+    pub mapping_pool: MappingPool,
+    // CNAME targets of local records are looked up by the authority of their zone
+    pub zones: ZoneAuthorities,
+}
+
+
 #[allow(dead_code)]
 pub struct TrspAuthority {
     origin: LowerName,
     domains_set: ArcDomainsSet,
-    forwarder: Arc<TokioAsyncResolver>,
-    inner_storage: RwLock<InnerStorage>,
+    // Names that are mapped and the rest
+    blocked_forwarder: Arc<UpstreamManager>,
+    forwarder: Arc<UpstreamManager>,
+    inner_storage: Arc<RwLock<InnerStorage>>,
     mapping_ipv4_subnet: Ipv4Net,
-    available_ipv4_inner_ips: RwLock<VecDeque<Ipv4Addr>>,
-    router: Box<dyn Router>,
+    available_ipv4_inner_ips: MappingPool,
+    router: Arc<dyn Router>,
+    zones: ZoneAuthorities,
     max_positive_ttl: Duration,
     max_negative_ttl: Duration,
+    // Of the blocked upstreams, for answers with mapped addresses
+    blocked_max_positive_ttl: Duration,
     max_record_lookup_cache_ttl: Duration,
     is_ipv6_mapping_enabled: bool,
     is_ipv6_forward_enabled: bool,
     cleanup_record_after_secs: Duration,
+    svcb_policy: SvcbPolicy,
     //forwarder_cache: RwLock<HashMap<LowerName, ForwarderCacheRecord>>,
 }
 
@@ -69,59 +104,43 @@ pub struct TrspAuthority {
 impl TrspAuthority {
 
     pub fn new(
+        origin: LowerName,
         domains_set: ArcDomainsSet,
-        forward_config: &ForwardConfig,
+        blocked_forwarder: Arc<UpstreamManager>,
+        forwarder: Arc<UpstreamManager>,
+        shared: SharedContext,
         options: &Options,
-
     ) -> Result<Self, Box<dyn Error>>
     {
         //let resolver = TrspAuthority::create_resolver(forward_config)?;
         let mapping_ipv4_subnet = options.dns_mapping_ipv4_subnet.clone();
-        let forwarder = TrspAuthority::create_forwarder(forward_config)?;
-        let vpn_subnet = VpnSubnet::V4(options.dns_vpn_ipv4_subnet);
-        let router = Box::new(Iptables::new(None, vpn_subnet, false, options.dns_mock_router));
-        router.init()?;
+        let max_positive_ttl = Duration::from_secs(options.dns_positive_max_ttl);
+        let blocked_max_positive_ttl = blocked_forwarder.options().positive_max_ttl
+            .unwrap_or(max_positive_ttl);
         let this = Self {
-            origin: LowerName::from_str(".").unwrap(),
+            origin,
             domains_set,
+            blocked_forwarder,
             forwarder,
-            inner_storage: RwLock::new(InnerStorage::new()),
+            inner_storage: shared.inner_storage,
             mapping_ipv4_subnet,
-            available_ipv4_inner_ips: RwLock::new(VecDeque::from_iter(mapping_ipv4_subnet.hosts())),
-            router,
-            max_positive_ttl: Duration::from_secs(options.dns_positive_max_ttl),
+            available_ipv4_inner_ips: shared.mapping_pool,
+            router: shared.router,
+            zones: shared.zones,
+            max_positive_ttl,
             max_negative_ttl: Duration::from_secs(options.dns_negative_max_ttl),
+            blocked_max_positive_ttl,
             max_record_lookup_cache_ttl: Duration::from_secs(options.dns_record_lookup_max_ttl),
             is_ipv6_mapping_enabled: options.dns_enable_ipv6_mapping,
             is_ipv6_forward_enabled: options.dns_enable_ipv6_forward,
             cleanup_record_after_secs: Duration::from_secs(options.dns_cleanup_record_after_secs),
+            svcb_policy: options.dns_svcb_policy,
             //forwarder_cache: RwLock::new(HashMap::with_capacity(FORWARDER_CACHE_SIZE)),
         };
         Ok(this)
     }
 
 
-    fn create_forwarder(forward_config: &ForwardConfig)
-        -> Result<Arc<TokioAsyncResolver>, Box<dyn Error>>
-    {
-        let name_servers = forward_config.name_servers.clone();
-        let mut options = forward_config.options.clone().unwrap_or_default();
-
-        if !options.preserve_intermediates {
-            warn!(
-                "preserve_intermediates set to false, which is invalid \
-                for a forwarder; switching to true"
-            );
-            options.preserve_intermediates = true;
-        }
-
-        let config = ResolverConfig::from_parts(None, vec![], name_servers);
-        let resolver = TokioAsyncResolver::tokio(config, options);
-
-        return Ok(Arc::new(resolver))
-    }
-
-
     pub async fn inner_lookup(&self, name: &LowerName, rtype: RecordType)
         -> Result<Lookup, ResolveError>
     {
@@ -169,7 +188,7 @@ impl TrspAuthority {
         Lookup::new_with_deadline(
             query,
             Arc::from(records_set.records_for_response()),
-            Instant::now() + self.max_positive_ttl,
+            Instant::now() + self.blocked_max_positive_ttl,
         )
 
     }
@@ -193,7 +212,7 @@ impl TrspAuthority {
         -> Result<Lookup, ResolveError>
     {
         // TODO UPDATE
-        let lookup = self.forwarder.lookup(name, rtype).await?;
+        let lookup = self.blocked_forwarder.lookup(name, rtype).await?;
         let lookup_time = Utc::now();
 
         let mut inner_storage = self.inner_storage.write().await;
@@ -223,7 +242,21 @@ impl TrspAuthority {
             }
         }
 
+        // CNAMEs which left the chain are hidden as stale addresses
+        let lookup_cnames: Vec<&RData> = lookup.records()
+            .iter()
+            .filter(|r| r.record_type() == RecordType::CNAME)
+            .filter_map(|r| r.data())
+            .collect();
+
         for record in record_set.records_mut() {
+            if record.is_cname() {
+                match record.rdata() {
+                    Some(data) if lookup_cnames.contains(&data) => record.unmark_for_cleanup(),
+                    _ => record.mark_for_cleanup(self.cleanup_record_after_secs),
+                }
+                continue
+            }
             if let Some(ip) = record.original_addr {
                 current_ips.push(ip);
                 if lookup_ips.contains(&ip) {
@@ -246,7 +279,7 @@ impl TrspAuthority {
                     _ => {}
                 }
             }
-            return Err(ResolveError::from("internal_error"))
+            return Err(ResolveError::from(ROUTER_FAILED))
         }
 
         if let Err(e) = inner_storage.upsert(name, rtype, &record_set) {
@@ -260,7 +293,7 @@ impl TrspAuthority {
                     _ => {}
                 }
             }
-            return Err(ResolveError::from("error_while_push_records_set"))
+            return Err(ResolveError::from(STORAGE_FAILED))
         }
 
         Ok(self.build_lookup(name, rtype, &record_set))
@@ -295,7 +328,7 @@ impl TrspAuthority {
                 ip.into()
             } else {
                 error!("Mapped ip set is empty");
-                return Err(ResolveError::from("Mapped ip set is empty"))
+                return Err(ResolveError::from(POOL_EXHAUSTED))
             };
             let ip_addr = if let Some(ip) = record.data().unwrap().ip_addr() {
                 ip
@@ -333,6 +366,193 @@ impl TrspAuthority {
         }
     }
 
+    // Records of the type, or the CNAME chain of local names with the
+    // forwarded (or mapped) answer of the first non-local target
+    async fn local_lookup(&self, name: &LowerName, rtype: RecordType, mut records: Vec<Record>)
+        -> Result<Lookup, LookupError>
+    {
+        let query = Query::query(Name::from(name), rtype);
+        let deadline = Instant::now() + self.max_positive_ttl;
+        let mut answers: Vec<Record> = vec![];
+        for _ in 0..MAX_LOCAL_CNAME_CHAIN {
+            let cname = records.iter()
+                .find(|r| r.record_type() == RecordType::CNAME && rtype != RecordType::CNAME)
+                .and_then(|r| r.data().and_then(|d| d.as_cname()).map(|c| (r.clone(), c.0.clone())));
+            let (cname, target) = match cname {
+                Some(c) => c,
+                None => {
+                    answers.extend(records.into_iter().filter(|r| r.record_type() == rtype));
+                    if answers.is_empty() {
+                        return Err(LookupError::for_name_exists())
+                    }
+                    return Ok(Lookup::new_with_deadline(query, Arc::from(answers), deadline))
+                },
+            };
+            answers.push(cname);
+            let target = LowerName::from(target);
+            records = match self.domains_set.load_full().local_records.find(&target) {
+                Some(r) => r,
+                None => {
+                    let res = match self.authority_of(&target) {
+                        Some(authority) => authority.lookup(&target, rtype, LookupOptions::default()).await,
+                        None => Err(LookupError::from(ResponseCode::Refused)),
+                    };
+                    match res {
+                        Ok(lookup) => answers.extend(lookup.0.records().iter().cloned()),
+                        // The chain ends with the CNAME
+                        Err(e) if e.is_nx_domain() || e.is_name_exists() => {},
+                        Err(e) => return Err(e),
+                    }
+                    return Ok(Lookup::new_with_deadline(query, Arc::from(answers), deadline))
+                },
+            };
+        }
+        warn!("CNAME chain of local records is too long: {}", name);
+        Err(LookupError::from(ResponseCode::ServFail))
+    }
+
+    // The authority of the most specific zone of the name
+    fn authority_of(&self, name: &LowerName) -> Option<Arc<TrspAuthority>> {
+        let zones = self.zones.read().unwrap();
+        zones.iter()
+            .filter(|(zone, _)| zone.zone_of(name))
+            .max_by_key(|(zone, _)| zone.num_labels())
+            .and_then(|(_, authority)| authority.upgrade())
+    }
+
+    // Names of the CNAME chain and addresses of the answer can make the name
+    // blocked, then the whole chain is looked up again and mapped
+    async fn forwarder_lookup_by_answer(&self, name: &LowerName, rtype: RecordType) -> Result<Lookup, ResolveError> {
+        let lookup = self.forwarder_lookup(name.clone(), rtype).await?;
+        if ! matches!(rtype, RecordType::A | RecordType::AAAA | RecordType::HTTPS | RecordType::SVCB) {
+            return Ok(lookup)
+        }
+        if let Some(reason) = self.answer_block_reason(name, &lookup).await {
+            info!("Answer of '{}' {} is blocked by {}", name, rtype, reason);
+            return match rtype {
+                RecordType::HTTPS | RecordType::SVCB => match self.svcb_policy {
+                    SvcbPolicy::Rewrite => self.rewrite_svcb_hints(lookup).await,
+                    SvcbPolicy::Nodata => Ok(self.empty_lookup(name, rtype)),
+                },
+                _ => self.add_blocked_domain(name, rtype).await,
+            }
+        }
+        Ok(lookup)
+    }
+
+    // Address hints of HTTPS/SVCB answers would let clients connect to
+    // blocked sites directly
+    async fn blocked_svcb_lookup(&self, name: &LowerName, rtype: RecordType) -> Result<Lookup, ResolveError> {
+        if self.svcb_policy == SvcbPolicy::Nodata {
+            return Ok(self.empty_lookup(name, rtype))
+        }
+        let lookup = self.blocked_forwarder.lookup(name.clone(), rtype).await?;
+        self.rewrite_svcb_hints(lookup).await
+    }
+
+    fn empty_lookup(&self, name: &LowerName, rtype: RecordType) -> Lookup {
+        let mut query = Query::new();
+        query.set_name(Name::from(name));
+        query.set_query_type(rtype);
+        Lookup::new_with_deadline(query, Arc::from([]), Instant::now() + self.max_negative_ttl)
+    }
+
+    async fn rewrite_svcb_hints(&self, lookup: Lookup) -> Result<Lookup, ResolveError> {
+        let mut records = Vec::with_capacity(lookup.records().len());
+        for record in lookup.records() {
+            let svcb = match record.data() {
+                Some(RData::SVCB(svcb)) => svcb,
+                Some(RData::HTTPS(https)) => &https.0,
+                _ => {
+                    records.push(record.clone());
+                    continue
+                },
+            };
+            // Hints are addresses of the target, "." is the owner name
+            let target = match svcb.target_name().is_root() {
+                true => record.name(),
+                false => svcb.target_name(),
+            };
+            let params = self.rewrite_hints(target, svcb.svc_params()).await;
+            let svcb = SVCB::new(svcb.svc_priority(), svcb.target_name().clone(), params);
+            let mut record = record.clone();
+            match record.record_type() {
+                RecordType::HTTPS => record.set_data(Some(RData::HTTPS(HTTPS(svcb)))),
+                _ => record.set_data(Some(RData::SVCB(svcb))),
+            };
+            records.push(record);
+        }
+        Ok(Lookup::new_with_deadline(lookup.query().clone(), Arc::from(records), lookup.valid_until()))
+    }
+
+    // ipv4hint points at the mapped addresses of the target, mapped addresses
+    // are IPv4 only so ipv6hint is removed
+    async fn rewrite_hints(&self, target: &Name, params: &[(SvcParamKey, SvcParamValue)])
+        -> Vec<(SvcParamKey, SvcParamValue)>
+    {
+        let mut rewritten = Vec::with_capacity(params.len());
+        for (key, value) in params {
+            match value {
+                SvcParamValue::Ipv4Hint(_) => {
+                    let mapped = self.mapped_ipv4s(&LowerName::from(target)).await;
+                    if ! mapped.is_empty() {
+                        rewritten.push((*key, SvcParamValue::Ipv4Hint(IpHint(mapped.into_iter().map(A).collect()))));
+                    }
+                },
+                SvcParamValue::Ipv6Hint(_) => {},
+                _ => rewritten.push((*key, value.clone())),
+            }
+        }
+        rewritten
+    }
+
+    // Mapped addresses of the A records of the name, allocated if there are none yet
+    async fn mapped_ipv4s(&self, name: &LowerName) -> Vec<Ipv4Addr> {
+        let lookup = match self.inner_lookup(name, RecordType::A).await {
+            Ok(l) => Ok(l),
+            Err(_) => self.add_blocked_domain(name, RecordType::A).await,
+        };
+        match lookup {
+            Ok(l) => l.iter()
+                .filter_map(|r| match r {
+                    RData::A(a) => Some(a.0),
+                    _ => None,
+                })
+                .collect(),
+            Err(e) => {
+                warn!("Hints of '{}' are removed, mapping error: {}", name, e);
+                vec![]
+            },
+        }
+    }
+
+    async fn answer_block_reason(&self, name: &LowerName, lookup: &Lookup) -> Option<String> {
+        let domains_set = self.domains_set.load_full();
+        // The queried name is checked already
+        let mut checked_names = vec![Name::from(name)];
+        for record in lookup.records() {
+            let mut names = vec![record.name().clone()];
+            if let Some(RData::CNAME(cname)) = record.data() {
+                names.push(cname.0.clone());
+            }
+            for n in names {
+                if checked_names.contains(&n) {
+                    continue
+                }
+                if domains_set.is_domain_blocked(LowerName::from(&n).to_string().as_ref()).await {
+                    return Some(format!("CNAME chain name '{}'", n))
+                }
+                checked_names.push(n);
+            }
+            for addr in record_addrs(record) {
+                if let Some(reason) = domains_set.addr_block_reason(&addr).await {
+                    return Some(format!("{} of {}", reason, addr))
+                }
+            }
+        }
+        None
+    }
+
     pub async fn add_blocked_domain(&self, name: &LowerName, rtype: RecordType)
         -> Result<Lookup, ResolveError>
     {
@@ -346,7 +566,7 @@ impl TrspAuthority {
         }
         drop(inner_storage);
 
-        let lookup = self.forwarder.lookup(name, rtype).await?;
+        let lookup = self.blocked_forwarder.lookup(name, rtype).await?;
         let mut record_set = ProxyRecordSet::new(
             name.to_string().as_ref(),
             Utc::now(),
@@ -366,7 +586,7 @@ impl TrspAuthority {
                     _ => {}
                 }
             }
-            return Err(ResolveError::from("internal_error"))
+            return Err(ResolveError::from(ROUTER_FAILED))
         }
 
         if let Err(e) = inner_storage.upsert(name, rtype, &record_set) {
@@ -380,7 +600,7 @@ impl TrspAuthority {
                     _ => {}
                 }
             }
-            return Err(ResolveError::from("error_while_push_records_set"))
+            return Err(ResolveError::from(STORAGE_FAILED))
         }
 
 
@@ -424,11 +644,27 @@ impl Authority for TrspAuthority {
         _lookup_options: LookupOptions,
     ) -> Result<Self::Lookup, LookupError> {
 
+        if let Some(records) = self.domains_set.load_full().local_records.find(name) {
+            debug!("local records: {} {}", name, rtype);
+            return self.local_lookup(name, rtype, records).await.map(ForwardLookup)
+        }
+
+        // Zones of the config can override special-use names
+        if self.origin.is_root() {
+            if let Some(res) = special_use::answer(name, rtype) {
+                debug!("special-use name: {} {}", name, rtype);
+                let query = Query::query(Name::from(name), rtype);
+                let deadline = Instant::now() + self.max_positive_ttl;
+                return res.map(|records| ForwardLookup(Lookup::new_with_deadline(query, Arc::from(records), deadline)))
+            }
+        }
+
         match rtype {
             RecordType::AAAA => {
                 if ! self.is_ipv6_forward_enabled {
+                    // NODATA, NXDOMAIN would hide A records of the name too
                     warn!("Ipv6 forward disabled: {} {}", name, rtype);
-                    return Err(LookupError::ResponseCode(ResponseCode::NXDomain))
+                    return Err(LookupError::for_name_exists())
                 }
             },
             _ => (),
@@ -444,11 +680,14 @@ impl Authority for TrspAuthority {
             match e.kind() {
                  ResolveErrorKind::Message("Not Found") => {
                     debug!("Not found '{}' {}' in internal storage", rtype, name);
-                    if self.domains_set.is_domain_blocked(name.to_string().as_ref()).await {
-                        self.add_blocked_domain(name, rtype).await
+                    if self.domains_set.load_full().is_domain_blocked(name.to_string().as_ref()).await {
+                        match rtype {
+                            RecordType::HTTPS | RecordType::SVCB => self.blocked_svcb_lookup(name, rtype).await,
+                            _ => self.add_blocked_domain(name, rtype).await,
+                        }
                     } else {
                         // self.forwarder.lookup(name.clone(), rtype).await
-                        self.forwarder_lookup(name.clone(), rtype).await
+                        self.forwarder_lookup_by_answer(name, rtype).await
                     }
 
                 }
@@ -489,3 +728,42 @@ impl Authority for TrspAuthority {
         )))
     }
 }
+
+
+// Address of A/AAAA records, ipv4hint and ipv6hint addresses of SVCB/HTTPS records
+fn record_addrs(record: &Record) -> Vec<IpAddr> {
+    let svcb = match record.data() {
+        Some(RData::SVCB(svcb)) => svcb,
+        Some(RData::HTTPS(https)) => &https.0,
+        data => return data.and_then(|d| d.ip_addr()).into_iter().collect(),
+    };
+    let mut addrs = vec![];
+    for (_, value) in svcb.svc_params() {
+        match value {
+            SvcParamValue::Ipv4Hint(hint) => addrs.extend(hint.0.iter().map(|a| IpAddr::V4(a.0))),
+            SvcParamValue::Ipv6Hint(hint) => addrs.extend(hint.0.iter().map(|a| IpAddr::V6(a.0))),
+            _ => {},
+        }
+    }
+    addrs
+}
+
+
+#[test]
+fn test_trsp_authority_record_addrs() {
+    use hickory_proto::rr::rdata::AAAA;
+
+    let name = Name::from_ascii("example.com.").unwrap();
+    let record = Record::from_rdata(name.clone(), 60, RData::A(A::new(10, 0, 0, 1)));
+    assert_eq!(record_addrs(&record), vec!["10.0.0.1".parse::<IpAddr>().unwrap()]);
+
+    let svcb = SVCB::new(1, Name::root(), vec![
+        (SvcParamKey::Ipv4Hint, SvcParamValue::Ipv4Hint(IpHint(vec![A::new(192, 0, 2, 1)]))),
+        (SvcParamKey::Ipv6Hint, SvcParamValue::Ipv6Hint(IpHint(vec![AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)]))),
+    ]);
+    let record = Record::from_rdata(name, 60, RData::HTTPS(HTTPS(svcb)));
+    assert_eq!(record_addrs(&record), vec![
+        "192.0.2.1".parse::<IpAddr>().unwrap(),
+        "2001:db8::1".parse::<IpAddr>().unwrap(),
+    ]);
+}
@@ -0,0 +1,567 @@
+use std::{
+    error::Error,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+use futures_util::future::{join_all, select_ok};
+use lru_cache::LruCache;
+use tokio::{sync::RwLock, task::JoinHandle};
+use tracing::{debug, error, info, warn};
+
+use hickory_proto::rr::{IntoName, Name, Record, RecordType};
+use hickory_resolver::{
+    config::{NameServerConfig, NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    lookup::Lookup,
+    TokioAsyncResolver,
+};
+use hickory_server::store::forwarder::ForwardConfig;
+
+use crate::options::UpstreamStrategy;
+
+
+// Failed lookups and probes in a row that make an upstream unhealthy
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+// Weight of the last probe or lookup in the average latency
+const LATENCY_WEIGHT: f64 = 0.3;
+const MONITOR_TICK: Duration = Duration::from_secs(1);
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+const UPSTREAMS_STATUS_FILENAME: &str = "upstreams_status.txt";
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct SelectionConfig {
+    pub strategy: UpstreamStrategy,
+    // Upstreams asked in parallel by the race strategy
+    pub race: usize,
+    // 0 disables probes, upstreams are marked by failed lookups only
+    pub health_check_interval: Duration,
+}
+
+#[derive(Debug, Default, Clone)]
+struct UpstreamStats {
+    // Of probes and lookups, upstream resolvers have no cache
+    latency: Option<Duration>,
+    // Lookups answered by the upstream
+    served: u64,
+    // Failed lookups and probes
+    errors: u64,
+    consecutive_failures: u32,
+    last_error: Option<String>,
+}
+
+impl UpstreamStats {
+    fn is_healthy(&self) -> bool {
+        self.consecutive_failures < MAX_CONSECUTIVE_FAILURES
+    }
+}
+
+struct Upstream {
+    name: String,
+    resolver: TokioAsyncResolver,
+    stats: RwLock<UpstreamStats>,
+}
+
+// The answer and the time it expires at
+type CachedAnswer = (Result<Lookup, ResolveError>, Instant);
+
+// Answers and NXDOMAIN/NODATA of the group, whichever upstream served them.
+// The least recently used answer is evicted when the cache is full
+struct AnswersCache {
+    answers: Mutex<LruCache<(Name, RecordType), CachedAnswer>>,
+}
+
+impl AnswersCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            answers: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    // With TTLs of the time left
+    fn get(&self, name: &Name, rtype: RecordType) -> Option<Result<Lookup, ResolveError>> {
+        let now = Instant::now();
+        let key = (name.clone(), rtype);
+        let mut answers = self.answers.lock().unwrap();
+        let (result, valid_until) = answers.get_mut(&key)?;
+        if *valid_until <= now {
+            answers.remove(&key);
+            return None
+        }
+        let ttl = valid_until.duration_since(now).as_secs().try_into().unwrap_or(u32::MAX);
+        Some(match result {
+            Ok(lookup) => {
+                let records: Vec<Record> = lookup.record_iter()
+                    .map(|r| {
+                        let mut record = r.clone();
+                        record.set_ttl(ttl);
+                        record
+                    })
+                    .collect();
+                Ok(Lookup::new_with_deadline(lookup.query().clone(), Arc::from(records), *valid_until))
+            },
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { query, soa, response_code, trusted, .. } => {
+                    Err(ResolveError::from(ResolveErrorKind::NoRecordsFound {
+                        query: query.clone(),
+                        soa: soa.clone(),
+                        negative_ttl: Some(ttl),
+                        response_code: *response_code,
+                        trusted: *trusted,
+                    }))
+                },
+                _ => Err(e.clone()),
+            },
+        })
+    }
+
+    // Failures aren't cached, the next lookup tries upstreams again
+    fn insert(&self, name: Name, rtype: RecordType, result: &Result<Lookup, ResolveError>) {
+        let now = Instant::now();
+        let valid_until = match result {
+            Ok(lookup) => lookup.valid_until(),
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { negative_ttl: Some(ttl), .. } => {
+                    now + Duration::from_secs((*ttl).into())
+                },
+                _ => return,
+            },
+        };
+        if valid_until <= now {
+            return
+        }
+        self.answers.lock().unwrap().insert((name, rtype), (result.clone(), valid_until));
+    }
+}
+
+
+// Resolvers of an upstream group. Every server address of the group is an
+// upstream with its own resolver, latency and error counters. Upstreams are
+// asked in the order of the strategy, healthy ones first; NXDOMAIN and NODATA
+// are answers, other errors move to the next upstream. The upstream which
+// served an answer is logged with it. Answers are cached by the group, so a
+// cached answer is served whichever upstream is next
+pub struct UpstreamManager {
+    group_name: String,
+    upstreams: Vec<Upstream>,
+    cache: AnswersCache,
+    options: ResolverOpts,
+    selection: SelectionConfig,
+    // Start of the round-robin order
+    next: AtomicUsize,
+}
+
+impl UpstreamManager {
+    pub fn new(
+        group_name: &str,
+        forward_config: &ForwardConfig,
+        selection: SelectionConfig,
+        monitor: &UpstreamsMonitor,
+    ) -> Result<Arc<Self>, Box<dyn Error>> {
+        let mut options = forward_config.options.clone().unwrap_or_default();
+        if !options.preserve_intermediates {
+            warn!(
+                "preserve_intermediates set to false, which is invalid \
+                for a forwarder; switching to true"
+            );
+            options.preserve_intermediates = true;
+        }
+        // The next upstream is the retry, the group has the cache
+        let mut upstream_options = options.clone();
+        upstream_options.attempts = 1;
+        upstream_options.cache_size = 0;
+
+        // udp and tcp of the same address are one upstream
+        let mut servers: Vec<(SocketAddr, Vec<NameServerConfig>)> = vec![];
+        for config in forward_config.name_servers.iter() {
+            match servers.iter_mut().find(|(addr, _)| *addr == config.socket_addr) {
+                Some((_, configs)) => configs.push(config.clone()),
+                None => servers.push((config.socket_addr, vec![config.clone()])),
+            }
+        }
+        if servers.is_empty() {
+            return Err(format!("Upstreams '{}': no servers", group_name).into())
+        }
+        let upstreams = servers.into_iter().map(|(_, configs)| {
+            let mut name_servers = NameServerConfigGroup::with_capacity(configs.len());
+            for config in configs.iter() {
+                name_servers.push(config.clone());
+            }
+            let resolver_config = ResolverConfig::from_parts(None, vec![], name_servers);
+            Upstream {
+                name: upstream_name(&configs),
+                resolver: TokioAsyncResolver::tokio(resolver_config, upstream_options.clone()),
+                stats: RwLock::new(UpstreamStats::default()),
+            }
+        }).collect();
+
+        let this = Arc::new(Self {
+            group_name: String::from(group_name),
+            upstreams,
+            cache: AnswersCache::new(options.cache_size),
+            options,
+            selection,
+            next: AtomicUsize::new(0),
+        });
+        monitor.add(&this);
+        Ok(this)
+    }
+
+    pub fn options(&self) -> &ResolverOpts {
+        &self.options
+    }
+
+    pub async fn lookup<N: IntoName>(&self, name: N, rtype: RecordType) -> Result<Lookup, ResolveError> {
+        let name = name.into_name()?;
+        if let Some(result) = self.cache.get(&name, rtype) {
+            debug!("'{}' {} is answered by the cache of '{}'", name, rtype, self.group_name);
+            return result
+        }
+        let result = self.lookup_upstreams(name.clone(), rtype).await;
+        self.cache.insert(name, rtype, &result);
+        result
+    }
+
+    async fn lookup_upstreams(&self, name: Name, rtype: RecordType) -> Result<Lookup, ResolveError> {
+        let mut health = Vec::with_capacity(self.upstreams.len());
+        for upstream in &self.upstreams {
+            let stats = upstream.stats.read().await;
+            health.push((stats.is_healthy(), stats.latency));
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let order = upstreams_order(self.selection.strategy, start, &health);
+
+        let mut rest = &order[..];
+        let mut last_error = None;
+        if self.selection.strategy == UpstreamStrategy::Race {
+            let racers_count = self.selection.race.clamp(1, order.len());
+            let racers = order[..racers_count].iter().map(|i| {
+                let name = name.clone();
+                Box::pin(async move {
+                    match self.lookup_upstream(&self.upstreams[*i], name, rtype).await {
+                        Err(e) if !is_answer(&e) => Err(e),
+                        result => Ok(result),
+                    }
+                })
+            });
+            match select_ok(racers).await {
+                Ok((result, _)) => return result,
+                Err(e) => last_error = Some(e),
+            }
+            rest = &order[racers_count..];
+        }
+        for i in rest {
+            match self.lookup_upstream(&self.upstreams[*i], name.clone(), rtype).await {
+                Err(e) if !is_answer(&e) => last_error = Some(e),
+                result => return result,
+            }
+        }
+        Err(last_error.unwrap_or_else(|| ResolveError::from(ResolveErrorKind::NoConnections)))
+    }
+
+    async fn lookup_upstream(&self, upstream: &Upstream, name: Name, rtype: RecordType)
+        -> Result<Lookup, ResolveError>
+    {
+        let start = Instant::now();
+        let result = upstream.resolver.lookup(name.clone(), rtype).await;
+        let latency = start.elapsed();
+        match &result {
+            Err(e) if !is_answer(e) => {
+                debug!("'{}' {}: upstream '{}' failed: {}", name, rtype, upstream.name, e);
+            },
+            _ => {
+                debug!(
+                    "'{}' {} is answered by upstream '{}' in {}ms",
+                    name, rtype, upstream.name, latency.as_millis(),
+                );
+            },
+        }
+        self.update_stats(upstream, &result, latency, true).await;
+        result
+    }
+
+    async fn probe(&self, upstream: &Upstream) {
+        let start = Instant::now();
+        let result = upstream.resolver.lookup(Name::root(), RecordType::NS).await;
+        self.update_stats(upstream, &result, start.elapsed(), false).await;
+    }
+
+    async fn update_stats(
+        &self,
+        upstream: &Upstream,
+        result: &Result<Lookup, ResolveError>,
+        latency: Duration,
+        is_lookup: bool,
+    ) {
+        let mut stats = upstream.stats.write().await;
+        match result {
+            Err(e) if !is_answer(e) => {
+                stats.errors += 1;
+                stats.consecutive_failures += 1;
+                stats.last_error = Some(e.to_string());
+                if stats.consecutive_failures == MAX_CONSECUTIVE_FAILURES {
+                    warn!("Upstream '{}' of '{}' is unhealthy: {}", upstream.name, self.group_name, e);
+                }
+            },
+            _ => {
+                if is_lookup {
+                    stats.served += 1;
+                }
+                if !stats.is_healthy() {
+                    info!("Upstream '{}' of '{}' is healthy again", upstream.name, self.group_name);
+                }
+                stats.consecutive_failures = 0;
+                stats.latency = Some(match stats.latency {
+                    Some(average) => average.mul_f64(1.0 - LATENCY_WEIGHT) + latency.mul_f64(LATENCY_WEIGHT),
+                    None => latency,
+                });
+            },
+        }
+    }
+
+    async fn check_health(&self) {
+        join_all(self.upstreams.iter().map(|u| self.probe(u))).await;
+        for upstream in &self.upstreams {
+            let stats = upstream.stats.read().await;
+            debug!(
+                "Upstream '{}' of '{}': healthy {}, latency {:?}, served {}, errors {}, last error {:?}",
+                upstream.name, self.group_name, stats.is_healthy(), stats.latency,
+                stats.served, stats.errors, stats.last_error,
+            );
+        }
+    }
+
+    // Tab separated: group, upstream, healthy, latency ms, served, errors, last error
+    async fn status(&self) -> String {
+        let mut data = String::new();
+        for upstream in &self.upstreams {
+            let stats = upstream.stats.read().await;
+            data.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                self.group_name,
+                upstream.name,
+                stats.is_healthy(),
+                stats.latency.map_or(String::from("-"), |l| l.as_millis().to_string()),
+                stats.served,
+                stats.errors,
+                stats.last_error.as_deref().unwrap_or("-"),
+            ));
+        }
+        data
+    }
+}
+
+
+struct MonitoredGroup {
+    manager: Weak<UpstreamManager>,
+    next_probe: Instant,
+    probe: Option<JoinHandle<()>>,
+}
+
+// Probes upstreams of all groups from one loop, every group by its interval,
+// and writes their status to "upstreams_status.txt" in the workdir.
+// Groups are removed when their managers are dropped
+#[derive(Clone)]
+pub struct UpstreamsMonitor {
+    workdir: PathBuf,
+    groups: Arc<Mutex<Vec<MonitoredGroup>>>,
+}
+
+impl UpstreamsMonitor {
+    pub fn new(workdir: &Path) -> Self {
+        Self {
+            workdir: workdir.to_path_buf(),
+            groups: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    fn add(&self, manager: &Arc<UpstreamManager>) {
+        self.groups.lock().unwrap().push(MonitoredGroup {
+            manager: Arc::downgrade(manager),
+            next_probe: Instant::now(),
+            probe: None,
+        });
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MONITOR_TICK);
+            let mut last_status = Instant::now();
+            loop {
+                interval.tick().await;
+                self.probe_due_groups();
+                if last_status.elapsed() >= STATUS_INTERVAL {
+                    if let Err(e) = self.write_status_file().await {
+                        error!("Error while writing upstreams status: {}", e);
+                    }
+                    last_status = Instant::now();
+                }
+            }
+        })
+    }
+
+    // Probes of a group run in their own task, so timeouts of its upstreams
+    // don't delay the others. Groups with probes disabled are marked by
+    // failed lookups only
+    fn probe_due_groups(&self) {
+        let now = Instant::now();
+        let mut groups = self.groups.lock().unwrap();
+        groups.retain(|g| g.manager.strong_count() > 0);
+        for group in groups.iter_mut() {
+            let is_probing = group.probe.as_ref().map_or(false, |p| !p.is_finished());
+            if is_probing || group.next_probe > now {
+                continue
+            }
+            let manager = match group.manager.upgrade() {
+                Some(m) => m,
+                None => continue,
+            };
+            let interval = manager.selection.health_check_interval;
+            if interval.is_zero() {
+                continue
+            }
+            group.next_probe = now + interval;
+            group.probe = Some(tokio::spawn(async move { manager.check_health().await }));
+        }
+    }
+
+    async fn write_status_file(&self) -> Result<(), std::io::Error> {
+        let managers: Vec<Arc<UpstreamManager>> = self.groups.lock().unwrap()
+            .iter()
+            .filter_map(|g| g.manager.upgrade())
+            .collect();
+        let mut data = String::new();
+        for manager in managers {
+            data.push_str(&manager.status().await);
+        }
+        tokio::fs::write(self.workdir.join(UPSTREAMS_STATUS_FILENAME), data).await
+    }
+}
+
+// Indexes of upstreams by (healthy, latency) in the order they are asked,
+// unhealthy ones are the last resort
+fn upstreams_order(strategy: UpstreamStrategy, start: usize, health: &[(bool, Option<Duration>)]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..health.len()).collect();
+    match strategy {
+        UpstreamStrategy::Failover => {},
+        UpstreamStrategy::RoundRobin => order.rotate_left(start % health.len().max(1)),
+        UpstreamStrategy::LowestLatency | UpstreamStrategy::Race => {
+            order.sort_by_key(|i| health[*i].1.unwrap_or(Duration::MAX))
+        },
+    }
+    order.sort_by_key(|i| !health[*i].0);
+    order
+}
+
+// NXDOMAIN and NODATA are answers of the upstream
+fn is_answer(error: &ResolveError) -> bool {
+    matches!(error.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+fn upstream_name(configs: &[NameServerConfig]) -> String {
+    let config = &configs[0];
+    match &config.tls_dns_name {
+        Some(name) if config.protocol.is_encrypted() => {
+            format!("{}://{}@{}", config.protocol, name, config.socket_addr)
+        },
+        // udp and tcp of a bare address
+        _ if configs.len() > 1 => config.socket_addr.to_string(),
+        _ => format!("{}://{}", config.protocol, config.socket_addr),
+    }
+}
+
+
+#[tokio::test]
+async fn test_upstream_manager_order() {
+    use hickory_resolver::config::Protocol;
+
+    let ms = |m| Some(Duration::from_millis(m));
+    let health = [(true, ms(50)), (false, ms(5)), (true, None), (true, ms(20))];
+    assert_eq!(upstreams_order(UpstreamStrategy::Failover, 0, &health), vec![0, 2, 3, 1]);
+    assert_eq!(upstreams_order(UpstreamStrategy::RoundRobin, 6, &health), vec![2, 3, 0, 1]);
+    assert_eq!(upstreams_order(UpstreamStrategy::LowestLatency, 0, &health), vec![3, 0, 2, 1]);
+    assert_eq!(upstreams_order(UpstreamStrategy::Race, 0, &health), vec![3, 0, 2, 1]);
+
+    let mut name_servers = NameServerConfigGroup::from_ips_clear(&["192.0.2.1".parse().unwrap()], 53, true);
+    let mut tls = NameServerConfig::new("192.0.2.2:853".parse().unwrap(), Protocol::Tls);
+    tls.tls_dns_name = Some(String::from("dns.example"));
+    name_servers.push(tls);
+    let selection = SelectionConfig {
+        strategy: UpstreamStrategy::Failover,
+        race: 2,
+        health_check_interval: Duration::ZERO,
+    };
+    let forward_config = ForwardConfig { name_servers, options: None };
+    let monitor = UpstreamsMonitor::new(&std::env::temp_dir());
+    let manager = UpstreamManager::new("default", &forward_config, selection, &monitor).unwrap();
+    let names: Vec<&str> = manager.upstreams.iter().map(|u| u.name.as_str()).collect();
+    assert_eq!(names, vec!["192.0.2.1:53", "tls://dns.example@192.0.2.2:853"]);
+
+    let upstream = &manager.upstreams[0];
+    let failure = Err(ResolveError::from(ResolveErrorKind::Timeout));
+    for _ in 0..MAX_CONSECUTIVE_FAILURES {
+        manager.update_stats(upstream, &failure, Duration::ZERO, true).await;
+    }
+    assert!(!upstream.stats.read().await.is_healthy());
+    let nxdomain = Err(ResolveError::from(ResolveErrorKind::NoRecordsFound {
+        query: Box::default(),
+        soa: None,
+        negative_ttl: None,
+        response_code: hickory_proto::op::ResponseCode::NXDomain,
+        trusted: true,
+    }));
+    manager.update_stats(upstream, &nxdomain, Duration::from_millis(10), true).await;
+    manager.update_stats(upstream, &nxdomain, Duration::from_millis(20), false).await;
+    let stats = upstream.stats.read().await;
+    assert!(stats.is_healthy());
+    assert_eq!((stats.served, stats.errors, stats.latency), (1, 3, ms(13)));
+    drop(stats);
+    assert!(manager.status().await.starts_with("default\t192.0.2.1:53\ttrue\t13\t1\t3\t"));
+}
+
+#[test]
+fn test_upstream_manager_cache() {
+    use hickory_proto::{op::{Query, ResponseCode}, rr::{rdata::A, RData}};
+
+    let cache = AnswersCache::new(2);
+    let name = Name::from_ascii("example.com.").unwrap();
+    let query = Query::query(name.clone(), RecordType::A);
+    let record = Record::from_rdata(name.clone(), 300, RData::A(A::new(192, 0, 2, 1)));
+    let valid_until = Instant::now() + Duration::from_secs(100);
+    let answer = Ok(Lookup::new_with_deadline(query.clone(), Arc::from([record]), valid_until));
+    cache.insert(name.clone(), RecordType::A, &answer);
+    let lookup = cache.get(&name, RecordType::A).unwrap().unwrap();
+    assert!(lookup.record_iter().all(|r| r.ttl() <= 100));
+    assert!(cache.get(&name, RecordType::AAAA).is_none());
+
+    let other = Name::from_ascii("other.com.").unwrap();
+    let nodata = Err(ResolveError::from(ResolveErrorKind::NoRecordsFound {
+        query: Box::new(query),
+        soa: None,
+        negative_ttl: Some(60),
+        response_code: ResponseCode::NoError,
+        trusted: true,
+    }));
+    cache.insert(other.clone(), RecordType::A, &nodata);
+    match cache.get(&other, RecordType::A).unwrap().unwrap_err().kind() {
+        ResolveErrorKind::NoRecordsFound { negative_ttl, .. } => assert!(negative_ttl.unwrap() <= 60),
+        e => panic!("Unexpected error {:?}", e),
+    }
+
+    // Failures aren't cached
+    let new = Name::from_ascii("new.com.").unwrap();
+    cache.insert(new.clone(), RecordType::A, &Err(ResolveError::from(ResolveErrorKind::Timeout)));
+    assert!(cache.get(&new, RecordType::A).is_none());
+    // The full cache evicts the least recently used answer
+    assert!(cache.get(&name, RecordType::A).is_some());
+    cache.insert(new.clone(), RecordType::A, &answer);
+    assert!(cache.get(&new, RecordType::A).is_some());
+    assert!(cache.get(&name, RecordType::A).is_some());
+    assert!(cache.get(&other, RecordType::A).is_none());
+}
+
@@ -0,0 +1,404 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+use hickory_resolver::config::{NameServerConfig, NameServerConfigGroup, Protocol, ResolverOpts};
+use serde::Deserialize;
+
+use crate::options::UpstreamStrategy;
+use super::upstream_manager::SelectionConfig;
+
+
+// The only path of DNS over HTTPS the resolver supports
+const HTTPS_PATH: &str = "/dns-query";
+
+
+// [dns.upstreams] section of the config file. Names that are mapped
+// (blocked) and the rest are resolved by separate groups, a group that
+// isn't set uses --dns-resolvers and the TTL options
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct UpstreamsConfig {
+    pub blocked: Option<UpstreamGroupConfig>,
+    pub default: Option<UpstreamGroupConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamProtocol {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+impl UpstreamProtocol {
+    fn default_port(&self) -> u16 {
+        match self {
+            Self::Udp | Self::Tcp => 53,
+            Self::Tls => 853,
+            Self::Https => 443,
+        }
+    }
+}
+
+impl FromStr for UpstreamProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "udp" => Ok(Self::Udp),
+            "tcp" => Ok(Self::Tcp),
+            "tls" => Ok(Self::Tls),
+            "https" => Ok(Self::Https),
+            _ => Err(format!("unsupported protocol '{}'", s)),
+        }
+    }
+}
+
+
+// Upstream server given as a URL, the server name is the TLS name (SNI)
+// and the address is where it's connected to:
+//   1.1.1.1, 1.1.1.1:5353              udp and tcp (a bare address)
+//   udp://1.1.1.1, tcp://[2606:4700::1111]:53
+//   tls://dns.quad9.net@9.9.9.9:853
+//   https://dns.google/dns-query@8.8.8.8
+// Ports are 53, 853 and 443 by default
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct UpstreamSpec {
+    // None for a bare address
+    pub protocol: Option<UpstreamProtocol>,
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+    pub tls_name: Option<String>,
+}
+
+impl UpstreamSpec {
+    // Protocol of a bare address: --dns-https-resolvers 1.1.1.1:443
+    pub fn or_protocol(mut self, protocol: UpstreamProtocol) -> Self {
+        self.protocol = self.protocol.or(Some(protocol));
+        self
+    }
+
+    // The TLS name is the address if neither the spec nor the group sets it
+    pub fn name_servers(&self, default_tls_name: Option<&str>) -> Vec<NameServerConfig> {
+        let protocols: &[Protocol] = match self.protocol {
+            None => &[Protocol::Udp, Protocol::Tcp],
+            Some(UpstreamProtocol::Udp) => &[Protocol::Udp],
+            Some(UpstreamProtocol::Tcp) => &[Protocol::Tcp],
+            Some(UpstreamProtocol::Tls) => &[Protocol::Tls],
+            Some(UpstreamProtocol::Https) => &[Protocol::Https],
+        };
+        let port = self.port.unwrap_or(self.protocol.unwrap_or(UpstreamProtocol::Udp).default_port());
+        protocols.iter().map(|protocol| {
+            let mut config = NameServerConfig::new(SocketAddr::new(self.ip, port), *protocol);
+            if protocol.is_encrypted() {
+                config.tls_dns_name = Some(
+                    self.tls_name.clone()
+                        .or(default_tls_name.map(String::from))
+                        .unwrap_or_else(|| self.ip.to_string())
+                );
+            }
+            config
+        }).collect()
+    }
+}
+
+impl FromStr for UpstreamSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (protocol, rest) = match s.split_once("://") {
+            Some((scheme, rest)) => (Some(UpstreamProtocol::from_str(scheme)?), rest),
+            None => (None, s),
+        };
+        let (name, addr) = match rest.rsplit_once('@') {
+            Some((name, addr)) => (Some(name), addr),
+            None => (None, rest),
+        };
+        let tls_name = match name.map(|n| n.split_once('/').unwrap_or((n, ""))) {
+            None => None,
+            Some((host, path)) => {
+                match protocol {
+                    Some(UpstreamProtocol::Tls) if path.is_empty() => {},
+                    Some(UpstreamProtocol::Https) if path.is_empty() || format!("/{}", path) == HTTPS_PATH => {},
+                    Some(UpstreamProtocol::Https) => {
+                        return Err(format!("'{}': only the {} path is supported", s, HTTPS_PATH))
+                    },
+                    _ => return Err(format!("'{}': server name is set for a protocol without TLS", s)),
+                }
+                if host.is_empty() {
+                    return Err(format!("'{}': empty server name", s))
+                }
+                Some(String::from(host))
+            },
+        };
+        let (ip, port) = match SocketAddr::from_str(addr) {
+            Ok(a) => (a.ip(), Some(a.port())),
+            Err(_) => match IpAddr::from_str(addr.trim_start_matches('[').trim_end_matches(']')) {
+                Ok(ip) => (ip, None),
+                Err(_) if name.is_none() && protocol.is_some() => {
+                    return Err(format!("'{}': address is not an IP, the server is given as name@ip", s))
+                },
+                Err(_) => return Err(format!("'{}': invalid address '{}'", s, addr)),
+            },
+        };
+        Ok(Self { protocol, ip, port, tls_name })
+    }
+}
+
+impl TryFrom<String> for UpstreamSpec {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::from_str(&s)
+    }
+}
+
+// Value parser of --dns-https-resolvers
+pub fn parse_https_upstream(s: &str) -> Result<UpstreamSpec, String> {
+    UpstreamSpec::from_str(s).map(|u| u.or_protocol(UpstreamProtocol::Https))
+}
+
+pub fn add_name_servers(
+    upstreams: &[UpstreamSpec],
+    default_tls_name: Option<&str>,
+    name_servers: &mut NameServerConfigGroup,
+) {
+    for upstream in upstreams {
+        for config in upstream.name_servers(default_tls_name) {
+            name_servers.push(config);
+        }
+    }
+}
+
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UpstreamGroupConfig {
+    // Protocol of servers given as bare addresses, udp and tcp if it
+    // isn't set
+    pub protocol: Option<UpstreamProtocol>,
+    // URLs of UpstreamSpec, protocols can be mixed
+    pub servers: Vec<UpstreamSpec>,
+    // Name of the server certificate for servers without a name in the
+    // URL, the address if it isn't set
+    pub tls_name: Option<String>,
+    pub timeout: Option<u64>,
+    // Override --dns-positive-max-ttl etc.
+    pub positive_min_ttl: Option<u64>,
+    pub positive_max_ttl: Option<u64>,
+    pub negative_min_ttl: Option<u64>,
+    pub negative_max_ttl: Option<u64>,
+    // Override --dns-upstream-strategy etc.
+    pub strategy: Option<UpstreamStrategy>,
+    pub race: Option<usize>,
+    pub health_check_interval_secs: Option<u64>,
+}
+
+impl UpstreamGroupConfig {
+    pub fn name_servers(&self) -> Result<NameServerConfigGroup, String> {
+        if self.servers.is_empty() {
+            return Err(String::from("upstream group has no servers"))
+        }
+        let servers: Vec<UpstreamSpec> = self.servers.iter()
+            .map(|s| match self.protocol {
+                Some(p) => s.clone().or_protocol(p),
+                None => s.clone(),
+            })
+            .collect();
+        let mut name_servers = NameServerConfigGroup::with_capacity(servers.len());
+        add_name_servers(&servers, self.tls_name.as_deref(), &mut name_servers);
+        Ok(name_servers)
+    }
+
+    // Options of the group over the defaults from the command line
+    pub fn resolver_options(&self, mut options: ResolverOpts) -> ResolverOpts {
+        if let Some(t) = self.timeout {
+            options.timeout = Duration::from_secs(t);
+        }
+        let ttl = |t: Option<u64>, default| t.map(Duration::from_secs).or(default);
+        options.positive_min_ttl = ttl(self.positive_min_ttl, options.positive_min_ttl);
+        options.positive_max_ttl = ttl(self.positive_max_ttl, options.positive_max_ttl);
+        options.negative_min_ttl = ttl(self.negative_min_ttl, options.negative_min_ttl);
+        options.negative_max_ttl = ttl(self.negative_max_ttl, options.negative_max_ttl);
+        options
+    }
+
+    pub fn selection(&self, mut selection: SelectionConfig) -> SelectionConfig {
+        selection.strategy = self.strategy.unwrap_or(selection.strategy);
+        selection.race = self.race.unwrap_or(selection.race);
+        if let Some(s) = self.health_check_interval_secs {
+            selection.health_check_interval = Duration::from_secs(s);
+        }
+        selection
+    }
+}
+
+
+#[test]
+fn test_upstream_spec_parse() {
+    let spec = UpstreamSpec::from_str("tls://dns.quad9.net@9.9.9.9").unwrap();
+    assert_eq!(spec.protocol, Some(UpstreamProtocol::Tls));
+    let name_servers = spec.name_servers(None);
+    assert_eq!(name_servers.len(), 1);
+    assert_eq!(name_servers[0].socket_addr, "9.9.9.9:853".parse().unwrap());
+    assert_eq!(name_servers[0].tls_dns_name.as_deref(), Some("dns.quad9.net"));
+
+    let spec = UpstreamSpec::from_str("https://dns.google/dns-query@8.8.8.8").unwrap();
+    let name_servers = spec.name_servers(None);
+    assert_eq!(name_servers[0].protocol, Protocol::Https);
+    assert_eq!(name_servers[0].socket_addr, "8.8.8.8:443".parse().unwrap());
+    assert_eq!(name_servers[0].tls_dns_name.as_deref(), Some("dns.google"));
+
+    // A bare address is udp and tcp
+    let name_servers = UpstreamSpec::from_str("1.1.1.1:5353").unwrap().name_servers(None);
+    assert_eq!(name_servers.len(), 2);
+    assert_eq!(name_servers[1].protocol, Protocol::Tcp);
+    assert_eq!(name_servers[1].socket_addr.port(), 5353);
+    let name_servers = UpstreamSpec::from_str("tcp://[2606:4700::1111]").unwrap().name_servers(None);
+    assert_eq!(name_servers[0].socket_addr, "[2606:4700::1111]:53".parse().unwrap());
+    // The IP is the name without one
+    let name_servers = parse_https_upstream("1.1.1.1").unwrap().name_servers(None);
+    assert_eq!(name_servers[0].protocol, Protocol::Https);
+    assert_eq!(name_servers[0].tls_dns_name.as_deref(), Some("1.1.1.1"));
+
+    for s in [
+        "quic://1.1.1.1", "udp://dns.google@8.8.8.8", "https://dns.google/resolve@8.8.8.8",
+        "https://dns.google", "tls://@9.9.9.9", "1.1.1",
+    ] {
+        assert!(UpstreamSpec::from_str(s).is_err(), "{}", s);
+    }
+}
+
+#[test]
+fn test_upstream_group_config() {
+    let group = UpstreamGroupConfig {
+        protocol: Some(UpstreamProtocol::Tls),
+        servers: vec![
+            "1.1.1.1".parse().unwrap(),
+            "https://dns.google@8.8.8.8".parse().unwrap(),
+            "udp://9.9.9.9".parse().unwrap(),
+        ],
+        tls_name: Some(String::from("cloudflare-dns.com")),
+        timeout: Some(2),
+        positive_min_ttl: None,
+        positive_max_ttl: Some(600),
+        negative_min_ttl: None,
+        negative_max_ttl: None,
+        strategy: Some(UpstreamStrategy::Race),
+        race: None,
+        health_check_interval_secs: Some(0),
+    };
+    let name_servers = group.name_servers().unwrap();
+    assert_eq!(name_servers.len(), 3);
+    assert_eq!(name_servers[0].protocol, Protocol::Tls);
+    assert_eq!(name_servers[0].tls_dns_name.as_deref(), Some("cloudflare-dns.com"));
+    assert_eq!(name_servers[1].tls_dns_name.as_deref(), Some("dns.google"));
+    assert_eq!(name_servers[2].protocol, Protocol::Udp);
+
+    let mut defaults = ResolverOpts::default();
+    defaults.negative_max_ttl = Some(Duration::from_secs(30));
+    let options = group.resolver_options(defaults);
+    assert_eq!(options.timeout, Duration::from_secs(2));
+    assert_eq!(options.positive_max_ttl, Some(Duration::from_secs(600)));
+    assert_eq!(options.negative_max_ttl, Some(Duration::from_secs(30)));
+    let selection = group.selection(SelectionConfig {
+        strategy: UpstreamStrategy::Failover,
+        race: 3,
+        health_check_interval: Duration::from_secs(30),
+    });
+    assert_eq!(selection.strategy, UpstreamStrategy::Race);
+    assert_eq!(selection.race, 3);
+    assert!(selection.health_check_interval.is_zero());
+
+    let group = UpstreamGroupConfig { servers: vec![], ..group };
+    assert!(group.name_servers().is_err());
+}
+
+#[tokio::test]
+async fn test_upstream_spec_tls_name_server() {
+    use std::sync::Arc;
+    use hickory_proto::{
+        op::{Message, MessageType},
+        rr::{rdata::A, RData, Record, RecordType},
+    };
+    use hickory_resolver::{config::ResolverConfig, TokioAsyncResolver};
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    // Certificate of the server name signed by the trust anchor
+    let mut ca_params = CertificateParams::new(vec![]);
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params.distinguished_name.push(DnType::CommonName, "trsp test CA");
+    let ca = Certificate::from_params(ca_params).unwrap();
+    let mut params = CertificateParams::new(vec![String::from("dns.test")]);
+    params.distinguished_name.push(DnType::CommonName, "dns.test");
+    let cert = Certificate::from_params(params).unwrap();
+    let server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(cert.serialize_der_with_signer(&ca).unwrap())],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let mut stream = match acceptor.accept(stream).await {
+                    Ok(s) => s,
+                    Err(_) => return,
+                };
+                while let Ok(len) = stream.read_u16().await {
+                    let mut buf = vec![0; len.into()];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    let request = Message::from_vec(&buf).unwrap();
+                    let mut response = Message::new();
+                    response.set_id(request.id())
+                        .set_message_type(MessageType::Response)
+                        .set_recursion_available(true)
+                        .add_queries(request.queries().to_vec());
+                    let name = request.queries()[0].name().clone();
+                    response.add_answer(Record::from_rdata(name, 60, RData::A(A::new(192, 0, 2, 1))));
+                    let data = response.to_vec().unwrap();
+                    stream.write_u16(data.len().try_into().unwrap()).await.unwrap();
+                    stream.write_all(&data).await.unwrap();
+                }
+            });
+        }
+    });
+
+    let mut options = ResolverOpts::default();
+    options.timeout = Duration::from_secs(2);
+    options.attempts = 1;
+    options.cache_size = 0;
+    let spec = UpstreamSpec::from_str(&format!("tls://dns.test@{}", addr)).unwrap();
+    let resolver = |client_config: Option<rustls::ClientConfig>| {
+        let mut name_servers = NameServerConfigGroup::new();
+        add_name_servers(std::slice::from_ref(&spec), None, &mut name_servers);
+        if let Some(client_config) = client_config {
+            name_servers = name_servers.with_client_config(Arc::new(client_config));
+        }
+        TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, vec![], name_servers), options.clone())
+    };
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&rustls::Certificate(ca.serialize_der().unwrap())).unwrap();
+    let client_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let lookup = resolver(Some(client_config))
+        .lookup("example.com.", RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(lookup.iter().next(), Some(&RData::A(A::new(192, 0, 2, 1))));
+    // The default roots don't trust the test anchor
+    assert!(resolver(None).lookup("example.com.", RecordType::A).await.is_err());
+}
@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    io::{self, Write},
+    net::IpAddr,
+    path::PathBuf,
+};
+use encoding_rs::WINDOWS_1251;
+use ipnet::IpNet;
+use serde::Serialize;
+use tokio::io::AsyncBufReadExt;
+
+
+// Separator of values inside the field: "1.1.1.1 | 2.2.2.0/24"
+const VALUES_SEPARATOR: char = '|';
+const FIELDS_COUNT: usize = 6;
+// "@<id>\t<authority>" lines of the records index define authorities
+const AUTHORITY_PREFIX: &str = "@";
+
+
+// Record of the registry dump.csv:
+// "ips;domain;urls;authority;decision number;decision date", cp1251
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZapretRecord {
+    pub ips: Vec<IpNet>,
+    // Empty for records blocked by ip/url only
+    pub domain: String,
+    pub urls: Vec<String>,
+    pub authority: String,
+    pub decision_number: String,
+    pub decision_date: String,
+}
+
+impl ZapretRecord {
+    // None for the "Updated: ..." header line
+    pub fn parse(line: &[u8]) -> Result<Option<Self>, String> {
+        let (line, _, had_errors) = WINDOWS_1251.decode(line);
+        if had_errors {
+            return Err(String::from("Error while decoding the record from cp1251"))
+        }
+        let line = line.trim_end_matches('\r');
+        if line.starts_with("Updated:") || line.trim().is_empty() {
+            return Ok(None)
+        }
+        let fields = split_fields(line)?;
+        let n = fields.len();
+        if n < FIELDS_COUNT {
+            return Err(format!("{} fields instead of {}", n, FIELDS_COUNT))
+        }
+        Ok(Some(Self {
+            ips: parse_ips(&fields[0]),
+            domain: String::from(fields[1].trim()),
+            urls: split_values(&fields[2]).map(String::from).collect(),
+            // Authority names can contain ";"
+            authority: fields[3..n - 2].join(";"),
+            decision_number: fields[n - 2].clone(),
+            decision_date: fields[n - 1].clone(),
+        }))
+    }
+}
+
+// Fields separated by ";", a quoted field can contain ";" and "" as a quote
+fn split_fields(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut is_quoted = false;
+    while let Some(c) = chars.next() {
+        match (c, is_quoted) {
+            ('"', false) if field.is_empty() => is_quoted = true,
+            ('"', true) => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    is_quoted = false;
+                }
+            },
+            (';', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+    if is_quoted {
+        return Err(String::from("unterminated quoted field"))
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+fn split_values(field: &str) -> impl Iterator<Item = &str> {
+    field.split(VALUES_SEPARATOR).map(|v| v.trim()).filter(|v| !v.is_empty())
+}
+
+// Invalid addresses are skipped, the domain of the record is still valid
+fn parse_ips(field: &str) -> Vec<IpNet> {
+    split_values(field)
+        .filter_map(|v| v.parse::<IpNet>().ok().or_else(|| v.parse::<IpAddr>().ok().map(IpNet::from)))
+        .collect()
+}
+
+
+// Decision metadata and addresses of the record, from the records index
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RecordMeta {
+    // Line of the dump
+    pub line: u64,
+    pub ips: Vec<IpNet>,
+    pub authority: String,
+    pub decision_number: String,
+    pub decision_date: String,
+}
+
+// Writes "<line>\t<ips>\t<authority id>\t<decision number>\t<decision date>"
+// lines, authority names are written once as "@<id>\t<authority>"
+pub struct RecordsIndexWriter {
+    writer: io::BufWriter<fs::File>,
+    authorities: HashMap<String, usize>,
+}
+
+impl RecordsIndexWriter {
+    pub fn create(filepath: &PathBuf) -> Result<Self, io::Error> {
+        Ok(Self {
+            writer: io::BufWriter::new(fs::File::create(filepath)?),
+            authorities: HashMap::new(),
+        })
+    }
+
+    pub fn write(&mut self, line: u64, record: &ZapretRecord) -> Result<(), io::Error> {
+        let authorities_count = self.authorities.len();
+        let id = *self.authorities.entry(record.authority.clone()).or_insert(authorities_count);
+        if id == authorities_count {
+            writeln!(self.writer, "{}{}\t{}", AUTHORITY_PREFIX, id, clean(&record.authority))?;
+        }
+        let ips: Vec<String> = record.ips.iter().map(|ip| ip.to_string()).collect();
+        writeln!(
+            self.writer,
+            "{}\t{}\t{}\t{}\t{}",
+            line, ips.join(","), id, clean(&record.decision_number), clean(&record.decision_date),
+        )
+    }
+
+    pub fn finish(mut self) -> Result<(), io::Error> {
+        self.writer.flush()
+    }
+}
+
+fn clean(value: &str) -> String {
+    value.replace(['\t', '\n'], " ")
+}
+
+// Calls `f` with every record of the index, until it returns false
+pub async fn read_records_index<F>(filepath: &PathBuf, mut f: F) -> Result<(), Box<dyn Error>>
+    where F: FnMut(RecordMeta) -> bool
+{
+    let file = tokio::fs::File::open(filepath).await?;
+    let mut lines = tokio::io::BufReader::new(file).lines();
+    let mut authorities: HashMap<String, String> = HashMap::new();
+    while let Some(line) = lines.next_line().await? {
+        if let Some(authority) = line.strip_prefix(AUTHORITY_PREFIX) {
+            if let Some((id, name)) = authority.split_once('\t') {
+                authorities.insert(String::from(id), String::from(name));
+            }
+            continue
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 5 {
+            return Err(format!("Invalid records index line: {}", line).into())
+        }
+        let meta = RecordMeta {
+            line: fields[0].parse()?,
+            ips: fields[1].split(',').filter_map(|ip| ip.parse().ok()).collect(),
+            authority: authorities.get(fields[2]).cloned().unwrap_or_default(),
+            decision_number: String::from(fields[3]),
+            decision_date: String::from(fields[4]),
+        };
+        if ! f(meta) {
+            break
+        }
+    }
+    Ok(())
+}
+
+pub async fn find_record(filepath: &PathBuf, line: u64) -> Result<Option<RecordMeta>, Box<dyn Error>> {
+    let mut found = None;
+    read_records_index(filepath, |meta| {
+        if meta.line == line {
+            found = Some(meta);
+            return false
+        }
+        true
+    }).await?;
+    Ok(found)
+}
+
+
+#[tokio::test]
+async fn test_zapret_record_parse() {
+    let encode = |line: &str| WINDOWS_1251.encode(line).0.into_owned();
+
+    let record = ZapretRecord::parse(&encode(
+        "1.1.1.1 | 2.2.2.0/24 | bad;сайт.рф;http://сайт.рф/ | https://сайт.рф/a;Генпрокуратура;27-31-2020/Ид2971-20;2020-01-01",
+    )).unwrap().unwrap();
+    assert_eq!(record.ips, vec!["1.1.1.1/32".parse::<IpNet>().unwrap(), "2.2.2.0/24".parse().unwrap()]);
+    assert_eq!(record.domain, "сайт.рф");
+    assert_eq!(record.urls, vec!["http://сайт.рф/", "https://сайт.рф/a"]);
+    assert_eq!(record.authority, "Генпрокуратура");
+    assert_eq!(record.decision_number, "27-31-2020/Ид2971-20");
+    assert_eq!(record.decision_date, "2020-01-01");
+
+    let record = ZapretRecord::parse(&encode(
+        "3.3.3.3;;\"http://a.ru/?x=1;y=\"\"2\"\"\";суд;2-1/2020;2020-02-02",
+    )).unwrap().unwrap();
+    assert_eq!(record.domain, "");
+    assert_eq!(record.urls, vec!["http://a.ru/?x=1;y=\"2\""]);
+    assert_eq!(record.decision_number, "2-1/2020");
+
+    let record = ZapretRecord::parse(&encode("4.4.4.4;b.ru;;ФНС;Решение;1;2020-03-03")).unwrap().unwrap();
+    assert_eq!(record.authority, "ФНС;Решение");
+    assert_eq!(record.decision_number, "1");
+
+    assert_eq!(ZapretRecord::parse(b"Updated: 2024-01-01 12:00:00 +0000").unwrap(), None);
+    assert!(ZapretRecord::parse(b"1.1.1.1;a.ru").is_err());
+    assert!(ZapretRecord::parse(b"1.1.1.1;\"a.ru;;;;").is_err());
+
+    let workdir = tempfile::tempdir().unwrap();
+    let index_filepath = workdir.path().join("records");
+    let mut writer = RecordsIndexWriter::create(&index_filepath).unwrap();
+    writer.write(2, &record).unwrap();
+    writer.write(3, &record).unwrap();
+    writer.finish().unwrap();
+    let meta = find_record(&index_filepath, 3).await.unwrap().unwrap();
+    assert_eq!(meta.ips, record.ips);
+    assert_eq!(meta.authority, "ФНС;Решение");
+    assert_eq!(meta.decision_date, "2020-03-03");
+    assert_eq!(find_record(&index_filepath, 4).await.unwrap(), None);
+}
@@ -9,6 +9,7 @@ use std::{
     env,
     fs,
     path::PathBuf, sync::Arc, process,
+    time::Duration,
 };
 
 
@@ -20,6 +21,10 @@ use tokio::{
 };
 
 
+const EXIT_OK: i32 = 0;
+const EXIT_SHUTDOWN_ERROR: i32 = 1;
+const EXIT_SHUTDOWN_TIMEOUT: i32 = 2;
+
 
 fn setup_logger(opts: &options::Options) {
     let stdout_log = tracing_subscriber::fmt::layer().pretty();
@@ -81,6 +86,26 @@ fn setup_workdir(opts: &options::Options) -> Result<PathBuf, Box<dyn Error>> {
 }
 
 
+// Returns process exit code
+async fn shutdown(
+    dns_server: Arc<Mutex<dns::server::DnsServer>>,
+    opts: &options::Options,
+) -> i32 {
+    let timeout = Duration::from_secs(opts.shutdown_timeout_secs);
+    let report = dns_server.lock().await.shutdown(timeout).await;
+    if ! report.errors.is_empty() {
+        error!("Error while shutdown: {}", report.errors.join(". "));
+        return EXIT_SHUTDOWN_ERROR
+    }
+    if report.drain_timed_out {
+        error!("Shutdown timed out after {:?}", timeout);
+        return EXIT_SHUTDOWN_TIMEOUT
+    }
+    warn!("Shutdown completed");
+    EXIT_OK
+}
+
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let options = options::Options::parse();
@@ -101,6 +126,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // };
 
     let dns_workdir = workdir.join("dns");
+
+    if let Some(options::Command::Explain { name }) = &options.command {
+        let dns_server = dns::server::DnsServer::new(&options, &dns_workdir);
+        let explanation = dns_server.explain(name).await?;
+        println!("{}", serde_json::to_string_pretty(&explanation)?);
+        return Ok(())
+    }
     let dns_server_arc = Arc::new(Mutex::new(dns::server::DnsServer::new(&options, &dns_workdir)));
     let dns_server = dns_server_arc.clone();
     let dns_handler = match dns_server.lock().await.start().await {
@@ -127,11 +159,18 @@ async fn main() -> Result<(), Box<dyn Error>> {
         loop {
             tokio::select! {
                 _ = s_hangup.recv() =>  {
-                    let mut dns_server = dns_server.lock().await;
-                    if let Err(e) = dns_server.reload().await {
+                    // The server isn't locked while domains are imported
+                    let reload = dns_server.lock().await.prepare_reload()
+                        .map_err(|e| e.to_string());
+                    let res = match reload {
+                        Ok(Some(reload)) => reload.run().await.map_err(|e| e.to_string()),
+                        Ok(None) => Ok(()),
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = res {
                         error!("Error while reload: {}", e)
                     } else {
-                        error!("Reload successfull")
+                        info!("Reload successful")
                     }
                 }
             }
@@ -139,6 +178,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     });
 
+    let mut s_terminate = signal(SignalKind::terminate())?;
+    let mut s_interrupt = signal(SignalKind::interrupt())?;
+
     tokio::select!  {
         // res = web_handler => {
         //     // TODO: Откуда тут взялся еще один unwrap() ?
@@ -163,6 +205,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 }
             }
         },
+        _ = s_terminate.recv() => {
+            warn!("SIGTERM received, shutting down");
+            process::exit(shutdown(dns_server_arc, &options).await);
+        },
+        _ = s_interrupt.recv() => {
+            warn!("SIGINT received, shutting down");
+            process::exit(shutdown(dns_server_arc, &options).await);
+        },
     }
     // TODO: Обработка ошибок от tokio
     Ok(())
@@ -1,9 +1,45 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
 use std::net::SocketAddr;
 use ipnet::Ipv4Net;
+use crate::dns::upstreams::{parse_https_upstream, UpstreamSpec};
+
+// Order in which upstreams of a group are asked, healthy ones first
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpstreamStrategy {
+    // In the configured order, the next one after an error
+    Failover,
+    // The first one is the next upstream on every query
+    RoundRobin,
+    // By the probed latency
+    LowestLatency,
+    // --dns-upstream-race of the fastest in parallel, the first answer wins
+    Race,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    #[clap(about="Print why the domain is or isn't routed through the tunnel, as JSON")]
+    Explain {
+        name: String,
+    },
+}
+
+// HTTPS/SVCB answers of blocked domains
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SvcbPolicy {
+    // ipv4hint points at the mapped addresses, ipv6hint is removed
+    Rewrite,
+    // Empty answer, clients fall back to A/AAAA queries
+    Nodata,
+}
 
 #[derive(Parser, Debug, Clone)]
 pub struct Options {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     #[clap(
         short = 'l',
         long = "log-level",
@@ -17,6 +53,23 @@ pub struct Options {
     #[clap(short = 'w', long = "workdir", default_value="", env = "TRSP_WORKDIR")]
     pub workdir: String,
 
+    #[clap(
+        short = 'c',
+        long = "config",
+        default_value="",
+        help="TOML config file, see config.example.toml",
+        env = "TRSP_CONFIG")
+    ]
+    pub config: String,
+
+    #[clap(
+        long,
+        default_value_t = 10,
+        help="Max time to wait for graceful shutdown on SIGTERM/SIGINT",
+        env = "TRSP_SHUTDOWN_TIMEOUT_SECS")
+    ]
+    pub shutdown_timeout_secs: u64,
+
     // DNS
     #[clap(long = "dns-mock-router", action, default_value="false", env = "TRSP_DNS_MOCK_ROUTER")]
     pub dns_mock_router: bool,
@@ -27,6 +80,15 @@ pub struct Options {
     #[clap(long = "dns-enable-ipv6-forward", default_value="false", action, env = "TRSP_DNS_ENABLE_IPV6_FORWARD")]
     pub dns_enable_ipv6_forward: bool,
 
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = SvcbPolicy::Rewrite,
+        help="HTTPS/SVCB answers of blocked domains: rewrite address hints or return no records",
+        env = "TRSP_DNS_SVCB_POLICY")
+    ]
+    pub dns_svcb_policy: SvcbPolicy,
+
     #[clap(
         long,
         default_value = "0.0.0.0:53",
@@ -67,23 +129,51 @@ pub struct Options {
 
     #[clap(
         long,
-        help="External plain resolvers",
+        help="External resolvers: 1.1.1.1:53 (udp and tcp), udp://ip, tcp://ip, \
+            tls://name@ip:853, https://name/dns-query@ip",
         value_delimiter = ';',
         env = "TRSP_DNS_RESOLVERS")
     ]
-    pub dns_resolvers: Option<Vec<SocketAddr>>,
+    pub dns_resolvers: Option<Vec<UpstreamSpec>>,
 
     #[clap(
         long,
-        help="External https resolvers",
+        help="External https resolvers, bare addresses are their own TLS names, \
+            https://name@ip sets one",
         value_delimiter = ';',
+        value_parser = parse_https_upstream,
         env = "TRSP_DNS_HTTPS_RESOLVERS")
     ]
-    pub dns_https_resolvers: Option<Vec<SocketAddr>>,
+    pub dns_https_resolvers: Option<Vec<UpstreamSpec>>,
 
     #[clap(long, action, env = "TRSP_DNS_HTTPS_RESOLVERS_ENABLED")]
     pub dns_https_resolvers_enabled: bool,
 
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = UpstreamStrategy::Failover,
+        help="Order in which upstream resolvers are asked",
+        env = "TRSP_DNS_UPSTREAM_STRATEGY")
+    ]
+    pub dns_upstream_strategy: UpstreamStrategy,
+
+    #[clap(
+        long,
+        default_value_t = 2,
+        help="Upstreams asked in parallel by the race strategy",
+        env = "TRSP_DNS_UPSTREAM_RACE")
+    ]
+    pub dns_upstream_race: usize,
+
+    #[clap(
+        long,
+        default_value_t = 30,
+        help="Interval of upstream health probes, 0 disables them",
+        env = "TRSP_DNS_UPSTREAM_HEALTH_CHECK_INTERVAL_SECS")
+    ]
+    pub dns_upstream_health_check_interval_secs: u64,
+
     #[clap(
         long,
         default_value = "https://raw.githubusercontent.com/zapret-info/z-i/master/dump.csv",
@@ -98,6 +188,68 @@ pub struct Options {
     ]
     pub dns_zapret_blocked_nxdomains_txt: String,
 
+    #[clap(
+        long,
+        action,
+        help="Treat answers in ip/subnets of the zapret dump as blocked",
+        env = "TRSP_DNS_ZAPRET_IPS")
+    ]
+    pub dns_zapret_ips: bool,
+
+    #[clap(
+        long,
+        default_value_t = 3600,
+        help="Default refresh interval of domain sources, 0 disables the refresh",
+        env = "TRSP_DNS_REFRESH_INTERVAL_SECS")
+    ]
+    pub dns_refresh_interval_secs: u64,
+
+    #[clap(
+        long,
+        default_value_t = 300,
+        help="Max random delay added to the refresh interval",
+        env = "TRSP_DNS_REFRESH_JITTER_SECS")
+    ]
+    pub dns_refresh_jitter_secs: u64,
+
+    #[clap(
+        long,
+        default_value_t = 60,
+        help="Retry delay after a failed refresh, doubled on each failure up to the refresh interval",
+        env = "TRSP_DNS_REFRESH_RETRY_SECS")
+    ]
+    pub dns_refresh_retry_secs: u64,
+
+    #[clap(
+        long,
+        help="Proxy for domain lists downloads: http://, socks5:// or socks5h:// url",
+        env = "TRSP_DNS_LISTS_PROXY")
+    ]
+    pub dns_lists_proxy: Option<String>,
+
+    #[clap(
+        long,
+        default_value_t = 10,
+        env = "TRSP_DNS_LISTS_CONNECT_TIMEOUT_SECS")
+    ]
+    pub dns_lists_connect_timeout_secs: u64,
+
+    #[clap(
+        long,
+        default_value_t = 30,
+        help="Max time to wait for the response and for each chunk of the list",
+        env = "TRSP_DNS_LISTS_READ_TIMEOUT_SECS")
+    ]
+    pub dns_lists_read_timeout_secs: u64,
+
+    #[clap(
+        long,
+        default_value_t = 2,
+        help="Additional download attempts for each list url",
+        env = "TRSP_DNS_LISTS_RETRIES")
+    ]
+    pub dns_lists_retries: u32,
+
     #[clap(
         long,
         default_value = "120",
@@ -172,6 +324,24 @@ pub struct Options {
     ]
     pub dns_disable_iptables_commands: bool,
 
+    #[clap(
+        long,
+        action,
+        default_value_t = false,
+        help="Don't remove iptables chain and DNAT rules on exit",
+        env = "TRSP_DNS_KEEP_RULES_ON_EXIT")
+    ]
+    pub dns_keep_rules_on_exit: bool,
+
+    #[clap(
+        long,
+        action,
+        default_value_t = false,
+        help="Map imported domains from the file in the workdir instead of keeping them in memory",
+        env = "TRSP_DNS_MMAP_IMPORTED_DOMAINS")
+    ]
+    pub dns_mmap_imported_domains: bool,
+
 
     // WEB
     #[clap(long, default_value = "0.0.0.0:8080", env = "TRSP_WEB_ADDR")]